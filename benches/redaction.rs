@@ -0,0 +1,76 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use serde_json::{json, Value};
+use umami_proxy::proxy::Redactor;
+
+/// What `traverse_and_redact` gets per event, from a plain page view to free text full of URLs
+fn events() -> Vec<(&'static str, Value)> {
+	vec![
+		(
+			"pageview",
+			json!({
+				"type": "event",
+				"payload": {
+					"website": "c2f0a46d-6a23-4a1c-9a4f-2c3b0d5f8e11",
+					"hostname": "www.nav.no",
+					"url": "/arbeid/dagpenger/soknad?steg=3",
+					"referrer": "https://www.google.com/",
+					"title": "Søknad om dagpenger - nav.no",
+					"language": "nb-NO",
+					"screen": "1920x1080",
+				}
+			}),
+		),
+		(
+			"pii",
+			json!({
+				"type": "event",
+				"payload": {
+					"website": "c2f0a46d-6a23-4a1c-9a4f-2c3b0d5f8e11",
+					"url": "/person/15019012317/kontakt?epost=ola.nordmann%40nav.no",
+					"title": "Kontaktinfo for Ola Nordmann",
+					"data": {
+						"melding": "Ring meg på 987 65 432 eller send til ola.nordmann@nav.no",
+						"konto": "1234.56.78903",
+						"adresse": "Storgata 12, 0155 Oslo",
+					},
+				}
+			}),
+		),
+		(
+			"urls",
+			json!({
+				"type": "event",
+				"payload": {
+					"website": "c2f0a46d-6a23-4a1c-9a4f-2c3b0d5f8e11",
+					"data": {
+						"tekst": "Se https://www.nav.no/sak/123e4567-e89b-12d3-a456-426614174000/vedlegg \
+							og www.nav.no/minside/utbetalinger, eller https://arbeidsplassen.nav.no/stillinger/stilling/ \
+							9f8e7d6c-5b4a-3c2d-1e0f-a1b2c3d4e5f6 for kari.nordmann@nav.no. Fra /home/ola/Dokumenter \
+							til https://www.nav.no/kontakt/98765432 og tilbake. ".repeat(4),
+					},
+				}
+			}),
+		),
+	]
+}
+
+fn redaction(c: &mut Criterion) {
+	let redactor = Redactor::default();
+	let mut group = c.benchmark_group("traverse_and_redact");
+	for (name, event) in events() {
+		group.bench_function(name, |b| {
+			b.iter_batched(
+				|| event.clone(),
+				|mut event| {
+					redactor.traverse_and_redact(&mut event);
+					event
+				},
+				BatchSize::SmallInput,
+			);
+		});
+	}
+	group.finish();
+}
+
+criterion_group!(benches, redaction);
+criterion_main!(benches);
@@ -33,7 +33,12 @@
       # Common vars
       cargoDetails = pkgs.lib.importTOML ./Cargo.toml;
       pname = cargoDetails.package.name;
-      src = craneLib.cleanCargoSource (craneLib.path ./.);
+      # The privacy rules and name lists in conf/ are compiled in
+      src = pkgs.lib.cleanSourceWith {
+        src = craneLib.path ./.;
+        filter = path: type:
+          (pkgs.lib.hasInfix "/conf/" path) || (craneLib.filterCargoSources path type);
+      };
       commonArgs = {
         inherit pname src;
 
@@ -140,11 +145,12 @@
         config = pkgs.stdenv.mkDerivation rec {
           name = "config";
           version = "1.0.0";
-          src = ./conf/conf.yaml;
+          src = ./conf;
           phases = ["installPhase"];
           installPhase = ''
             mkdir -p $out/conf
-            cp  ${src} $out/conf/conf.yaml
+            cp  ${src}/conf.yaml $out/conf/conf.yaml
+            cp  ${src}/umami-proxy.yaml $out/conf/umami-proxy.yaml
           '';
         };
         spec = let
@@ -1,21 +1,1334 @@
+use std::collections::{BTreeMap, HashSet};
 use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 
-#[derive(Clone, Debug)]
-/// Umami Upstream
+use serde::Deserialize;
+
+/// Points at the proxy config file. Falls back to `DEFAULT_CONFIG_PATH`, and to the built-in
+/// defaults (plus env overrides) if that file doesn't exist either
+pub const CONFIG_PATH_ENV: &str = "UMAMI_PROXY_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "./conf/umami-proxy.yaml";
+/// Env vars starting with this override any setting, with `__` between the parts of its path:
+/// `UMAMI_PROXY__LIMITS__MAX_FIELD_LENGTH=1000`. The value is read as YAML
+pub const OVERRIDE_ENV_PREFIX: &str = "UMAMI_PROXY__";
+
+/// Anything longer than this many characters is truncated by `validate.rs`
+pub const DEFAULT_MAX_FIELD_LENGTH: usize = 500;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
+	/// Where events go unless a route says otherwise
+	pub upstream: Upstream,
+	/// Extra upstreams that `routes` can point at, by name
+	pub upstreams: BTreeMap<String, Upstream>,
+	/// Checked in order, the first match picks the upstream
+	pub routes: Vec<Route>,
+	pub dns: Dns,
+	pub health_check: HealthCheck,
+	pub circuit_breaker: CircuitBreaker,
+	pub listen: Listen,
+	pub limits: Limits,
+	pub redaction: Redaction,
+	/// What's forwarded of the client's request headers
+	pub headers: HeaderPolicy,
+	pub bots: BotPolicy,
+	pub k8s: K8s,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Umami Upstream
+pub struct Upstream {
 	pub host: String,
 	pub sni: Option<String>,
-	pub port: String,
+	pub port: u16,
 	pub path: Option<String>,
+	/// How requests are spread over the addresses `host` resolves to
+	pub balance: Balance,
+	/// Only used when `sni` is set, which is what turns TLS on
+	pub tls: UpstreamTls,
 }
 
-impl Config {
-	pub fn new() -> Self {
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTls {
+	/// PEM bundle to verify Umami's certificate with, instead of the system roots
+	pub ca: Option<PathBuf>,
+	/// Presented to Umami for mutual TLS
+	pub client: Option<Tls>,
+	pub verify_cert: bool,
+	pub verify_hostname: bool,
+	/// Accepted as the certificate's name besides the SNI
+	pub alternative_cn: Option<String>,
+	/// Offer HTTP/2 (falling back to HTTP/1.1) over ALPN
+	pub http2: bool,
+}
+
+impl Default for UpstreamTls {
+	fn default() -> Self {
+		Self {
+			ca: None,
+			client: None,
+			verify_cert: true,
+			verify_hostname: true,
+			alternative_cn: None,
+			http2: false,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Balance {
+	#[default]
+	RoundRobin,
+	/// The address with the fewest requests in flight
+	LeastConnections,
+}
+
+impl Upstream {
+	fn validate(&self, field: &str, violations: &mut Vec<Violation>) {
+		if self.port == 0 {
+			violations.push(Violation::new(format!("{field}.port"), "must be non-zero"));
+		}
+		if let Some(path) = &self.path {
+			if !path.starts_with('/') {
+				violations.push(Violation::new(
+					format!("{field}.path"),
+					"must start with '/'",
+				));
+			}
+		}
+		if self.sni.is_none() && self.tls != UpstreamTls::default() {
+			violations.push(Violation::new(
+				format!("{field}.tls"),
+				"has no effect without `sni`, which turns TLS on",
+			));
+		}
+	}
+}
+
+impl Default for Upstream {
+	fn default() -> Self {
+		Self {
+			host: String::new(),
+			sni: None,
+			port: 80,
+			path: None,
+			balance: Balance::default(),
+			tls: UpstreamTls::default(),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Sends matching requests to one of the named `upstreams`. A route matches when every criterion
+/// it sets matches, and a criterion matches when any of its values does
+pub struct Route {
+	pub upstream: String,
+	/// Origin hosts, either exact (`www.nav.no`) or a subdomain wildcard (`*.intern.nav.no`)
+	pub origins: Vec<String>,
+	pub path_prefixes: Vec<String>,
+	/// Umami `payload.website` ids
+	pub website_ids: Vec<String>,
+	/// k8s namespaces of the app owning `payload.hostname`
+	pub namespaces: Vec<String>,
+}
+
+impl Route {
+	/// Website ids and namespaces live in the body, so it has to be read before picking a peer
+	pub fn needs_body(&self) -> bool {
+		!self.website_ids.is_empty() || !self.namespaces.is_empty()
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Upstream hosts are resolved in the background and cached, not per request
+pub struct Dns {
+	pub refresh_secs: u64,
+}
+
+impl Default for Dns {
+	fn default() -> Self {
+		Self { refresh_secs: 30 }
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Active checks against every upstream address. Unhealthy addresses get no traffic
+pub struct HealthCheck {
+	/// Umami path that answers 200 when healthy, e.g. `/api/heartbeat`. Unset disables the checks
+	pub path: Option<String>,
+	pub interval_secs: u64,
+	/// Consecutive failed checks before an address is taken out
+	pub unhealthy_after: usize,
+	/// Consecutive good checks before it's put back
+	pub healthy_after: usize,
+}
+
+impl Default for HealthCheck {
+	fn default() -> Self {
+		Self {
+			path: None,
+			interval_secs: 10,
+			unhealthy_after: 3,
+			healthy_after: 1,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Passive failure counting per upstream. Once open, requests are answered with 503 right away
+pub struct CircuitBreaker {
+	pub enabled: bool,
+	/// Consecutive connect errors/timeouts that open the breaker
+	pub failure_threshold: u32,
+	/// How long it stays open before a single trial request is let through
+	pub open_secs: u64,
+}
+
+impl Default for CircuitBreaker {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			failure_threshold: 5,
+			open_secs: 30,
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Where each of the three services listens, every service can have several listeners
+pub struct Listen {
+	pub proxy: Vec<Listener>,
+	pub probes: Vec<Listener>,
+	pub metrics: Vec<Listener>,
+}
+
+impl Default for Listen {
+	fn default() -> Self {
+		Self {
+			proxy: vec![Listener::tcp("0.0.0.0:6191")],
+			probes: vec![Listener::tcp("0.0.0.0:6969")],
+			metrics: vec![Listener::tcp("0.0.0.0:9090")],
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Either a TCP `address` (optionally with `tls`) or a `unix` socket path
+pub struct Listener {
+	pub address: Option<String>,
+	pub unix: Option<PathBuf>,
+	pub tls: Option<Tls>,
+}
+
+impl Listener {
+	pub fn tcp(address: &str) -> Self {
+		Self {
+			address: Some(address.into()),
+			..Self::default()
+		}
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// PEM files, the cert file may hold the whole chain. Both are re-read when they change
+pub struct Tls {
+	pub cert: PathBuf,
+	pub key: PathBuf,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+	pub max_field_length: usize,
+}
+
+impl Default for Limits {
+	fn default() -> Self {
+		Self {
+			max_field_length: DEFAULT_MAX_FIELD_LENGTH,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Key lists and privacy patterns used by `redact::traverse_and_redact`
+pub struct Redaction {
+	/// Removed from the event entirely
+	pub drop_keys: KeyList,
+	/// Never redacted
+	pub skip_keys: KeyList,
+	/// Always replaced with `[PROXY]`, whatever the value looks like
+	pub advertising_id_keys: KeyList,
+	/// URL-ish fields where paths are expected and shouldn't be redacted as filepaths
+	pub filepath_exclusion_keys: KeyList,
+	/// Metadata fields where capitalized words are structural, not names
+	pub name_exclusion_keys: KeyList,
+	/// Privacy pattern labels (e.g. `PROXY-ADDRESS`) that are switched off everywhere
+	pub disabled_labels: HashSet<String>,
+	/// Privacy patterns to use instead of the built-in `conf/privacy-rules.yaml`
+	pub rules_file: Option<PathBuf>,
+	/// Keys added to the lists above for events with this website id
+	pub websites: BTreeMap<String, WebsiteKeys>,
+	/// Checked in order, the first one whose path matches decides what happens to a field
+	pub field_rules: Vec<FieldRule>,
+	/// What happens to query parameters in URL fields, the first rule naming a parameter decides.
+	/// Values of other parameters go through the privacy patterns
+	pub url_params: Vec<UrlParamRule>,
+	pub pseudonymize: Pseudonymize,
+	pub fnr_matching: FnrMatching,
+	/// Fields under these keys have their whole value replaced with the label, whatever it looks
+	/// like. The first entry whose keys match decides
+	pub sensitive_keys: Vec<SensitiveKeys>,
+	pub numbers: Numbers,
+	/// Object keys go through the privacy patterns too. Keys that end up the same get `#2`, `#3`
+	/// and so on added
+	pub redact_keys: bool,
+	/// A candidate rule set to try out on live traffic
+	pub shadow: Option<Shadow>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SensitiveKeys {
+	pub label: String,
+	pub keys: KeyList,
+	/// Words that can come in front of `keys` when they're fuzzy, like `bruker` in `brukerNavn`.
+	/// Keep to words that say whose it is, `skjemaNavn` isn't anybody's name
+	#[serde(default)]
+	pub allow_prefix: Vec<String>,
+}
+
+impl SensitiveKeys {
+	fn fuzzy(label: &str, keys: &[&str]) -> Self {
+		Self {
+			label: label.into(),
+			keys: KeyList::fuzzy(keys),
+			allow_prefix: ["bruker", "kontakt", "person", "user", "contact"]
+				.map(String::from)
+				.to_vec(),
+		}
+	}
+}
+
+/// Integers in the event are checked against these labels' patterns, like strings are
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Numbers {
+	pub labels: HashSet<String>,
+	pub replacement: NumberReplacement,
+}
+
+impl Default for Numbers {
+	fn default() -> Self {
+		Self {
+			labels: [
+				"PROXY-FNR",
+				"PROXY-PHONE",
+				"PROXY-ACCOUNT",
+				"PROXY-ORG-NUMBER",
+			]
+			.into_iter()
+			.map(String::from)
+			.collect(),
+			replacement: NumberReplacement::default(),
+		}
+	}
+}
+
+/// What a redacted number becomes
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NumberReplacement {
+	/// The label as a string, `"[PROXY-PHONE]"`
+	#[default]
+	String,
+	Null,
+	/// `0`, for consumers that need the field to stay a number
+	Zero,
+}
+
+/// Runs next to the active redaction on every event and only counts where the two disagree,
+/// what's forwarded doesn't change
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Shadow {
+	/// The candidate patterns, same format as `rules_file`
+	pub rules_file: PathBuf,
+	/// Used instead of `disabled_labels` for the candidate, when set
+	#[serde(default)]
+	pub disabled_labels: Option<HashSet<String>>,
+	/// The paths of every `log_every`th event that differs are logged
+	#[serde(default = "Shadow::default_log_every")]
+	pub log_every: u64,
+}
+
+impl Shadow {
+	const fn default_log_every() -> u64 {
+		100
+	}
+}
+
+/// What `PROXY-FNR` redacts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FnrMatching {
+	/// Fødselsnummer, D-, H- and synthetic numbers with a valid date and control digits
+	#[default]
+	Validated,
+	/// Any run of 11 digits that isn't part of a hex string, valid or not
+	Conservative,
+}
+
+/// Matches of these labels become `[<label>:<hash>]`, e.g. `[PROXY-EMAIL:3f9a1c]`, so the same
+/// value gets the same hash and distinct values can still be counted
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pseudonymize {
+	pub labels: HashSet<String>,
+	/// Secret the hash keys are derived from, needed when `labels` isn't empty and for
+	/// `action: hash`
+	pub key_file: Option<PathBuf>,
+	/// A new key is derived every `rotation_hours` (counted from the Unix epoch), hashes only
+	/// stay the same within one period
+	pub rotation_hours: u64,
+}
+
+impl Default for Pseudonymize {
+	fn default() -> Self {
+		Self {
+			labels: HashSet::new(),
+			key_file: None,
+			rotation_hours: 24,
+		}
+	}
+}
+
+/// A field picked out by its path, e.g. `payload.data.*.email` or `events[*].user_properties`.
+/// `*` is any key or array index, `[*]` any array index and `[2]` the third element
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldRule {
+	pub path: String,
+	pub action: FieldAction,
+	/// What the field is replaced with, as `[<label>]`. Only for `action: label`
+	pub label: Option<String>,
+}
+
+/// These win over the key lists and the privacy patterns, for the field and everything under it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FieldAction {
+	/// Removed from the event
+	Drop,
+	/// Left exactly as it is
+	Keep,
+	/// Replaced with `[<label>]`
+	Label,
+	/// Replaced with a truncated HMAC of the value, keyed like `pseudonymize`, so equal values
+	/// stay equal until the key rotates. Needs `pseudonymize.key_file`
+	Hash,
+}
+
+/// Query parameters by name, e.g. `utm_*` with `match: glob`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UrlParamRule {
+	pub params: KeyList,
+	/// `drop` removes the whole parameter, the others only touch its value
+	pub action: FieldAction,
+	/// Only for `action: label`
+	pub label: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebsiteKeys {
+	pub drop_keys: KeyList,
+	pub skip_keys: KeyList,
+	pub advertising_id_keys: KeyList,
+	pub filepath_exclusion_keys: KeyList,
+	pub name_exclusion_keys: KeyList,
+}
+
+/// A plain list of keys is matched exactly, `{ match: glob, keys: [...] }` picks how
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "KeyListRepr")]
+pub struct KeyList {
+	pub matching: KeyMatch,
+	pub keys: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyMatch {
+	#[default]
+	Exact,
+	CaseInsensitive,
+	/// `*` is any run of characters, `?` is any one character. Case-sensitive
+	Glob,
+	/// Case and separators don't matter: `epost` matches `ePost` and `e_post`, but not
+	/// `epostVarsel` or `kontaktEpost`
+	Fuzzy,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyListRepr {
+	Keys(Vec<String>),
+	Matched(MatchedKeys),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MatchedKeys {
+	#[serde(rename = "match", default)]
+	matching: KeyMatch,
+	keys: Vec<String>,
+}
+
+impl From<KeyListRepr> for KeyList {
+	fn from(repr: KeyListRepr) -> Self {
+		match repr {
+			KeyListRepr::Keys(keys) => Self {
+				matching: KeyMatch::Exact,
+				keys,
+			},
+			KeyListRepr::Matched(MatchedKeys { matching, keys }) => Self { matching, keys },
+		}
+	}
+}
+
+impl KeyList {
+	pub fn exact(keys: &[&str]) -> Self {
 		Self {
-			host: env::var("UMAMI_HOST").expect("Env var 'UMAMI_HOST' needs to be set"),
-			sni: env::var("UMAMI_SNI").ok(),
-			port: env::var("UMAMI_PORT").expect("Env var 'UMAMI_PORT' needs to be set"),
-			path: env::var("UMAMI_PATH").ok(),
+			matching: KeyMatch::Exact,
+			keys: keys.iter().map(ToString::to_string).collect(),
 		}
 	}
+
+	pub fn fuzzy(keys: &[&str]) -> Self {
+		Self {
+			matching: KeyMatch::Fuzzy,
+			keys: keys.iter().map(ToString::to_string).collect(),
+		}
+	}
+}
+
+impl Default for Redaction {
+	fn default() -> Self {
+		Self {
+			drop_keys: KeyList::exact(&["ip_address"]),
+			skip_keys: KeyList::exact(&["api_key", "device_id", "website"]),
+			advertising_id_keys: KeyList::exact(&[
+				"idfa",
+				"idfv",
+				"adid",
+				"gaid",
+				"android_id",
+				"aaid",
+				"msai",
+				"advertising_id",
+			]),
+			filepath_exclusion_keys: KeyList::exact(&[
+				"path",
+				"href",
+				"destinasjon",
+				"url",
+				"link",
+				"pathname",
+				"linkText",
+				"destination",
+				"url_path",
+				"fra",
+				"lenketekst",
+				"lenkesti",
+				"newLocation",
+				"prevLocation",
+			]),
+			name_exclusion_keys: KeyList::exact(&[
+				"komponent",
+				"lenketekst",
+				"linkText",
+				"breadcrumbs",
+				"pageType",
+				"pageTheme",
+				"employer",
+				"seksjon",
+				"valg",
+				"jobTitle",
+				"occupationLevel2",
+				"enhet",
+				"filter",
+				"organisasjoner",
+				"destinasjon",
+				"location",
+				"arbeidssted",
+				"kilde",
+				"skjemanavn",
+				"lenkegruppe",
+				"descriptionId",
+				"tema",
+				"innholdstype",
+				"yrkestittel",
+				"tlbhrNavn",
+			]),
+			disabled_labels: HashSet::new(),
+			rules_file: None,
+			websites: BTreeMap::new(),
+			field_rules: Vec::new(),
+			url_params: vec![
+				UrlParamRule {
+					params: KeyList {
+						matching: KeyMatch::Glob,
+						keys: vec!["utm_*".into()],
+					},
+					action: FieldAction::Keep,
+					label: None,
+				},
+				UrlParamRule {
+					params: KeyList::exact(&["token", "access_token", "id_token", "refresh_token"]),
+					action: FieldAction::Drop,
+					label: None,
+				},
+				UrlParamRule {
+					params: KeyList::exact(&["q", "query", "search", "k", "ord"]),
+					action: FieldAction::Label,
+					label: Some("PROXY-SEARCH".into()),
+				},
+			],
+			pseudonymize: Pseudonymize::default(),
+			fnr_matching: FnrMatching::default(),
+			// More specific categories first, `emailAddress` is an email
+			sensitive_keys: vec![
+				SensitiveKeys::fuzzy(
+					"PROXY-FNR",
+					&[
+						"fnr",
+						"fodselsnummer",
+						"fødselsnummer",
+						"personnummer",
+						"personident",
+						"dnummer",
+						"ssn",
+					],
+				),
+				SensitiveKeys::fuzzy(
+					"PROXY-EMAIL",
+					&[
+						"email",
+						"epost",
+						"emailadresse",
+						"epostadresse",
+						"emailaddress",
+					],
+				),
+				SensitiveKeys::fuzzy(
+					"PROXY-PHONE",
+					&[
+						"telefon",
+						"telefonnummer",
+						"tlf",
+						"mobil",
+						"mobilnummer",
+						"phone",
+						"phonenumber",
+						"mobile",
+					],
+				),
+				SensitiveKeys::fuzzy(
+					"PROXY-NAME",
+					&[
+						"navn",
+						"fornavn",
+						"mellomnavn",
+						"etternavn",
+						"fulltnavn",
+						"firstname",
+						"middlename",
+						"lastname",
+						"surname",
+						"fullname",
+					],
+				),
+				SensitiveKeys::fuzzy(
+					"PROXY-ADDRESS",
+					&[
+						"adresse",
+						"postadresse",
+						"bostedsadresse",
+						"gateadresse",
+						"address",
+						"streetaddress",
+					],
+				),
+				SensitiveKeys::fuzzy(
+					"PROXY-SECRET",
+					&[
+						"password",
+						"passord",
+						"passwd",
+						"secret",
+						"apikey",
+						"clientsecret",
+					],
+				),
+			],
+			numbers: Numbers::default(),
+			redact_keys: true,
+			shadow: None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Applied before the proxy sets its own headers (`Host`, the geo headers), which always go through
+pub struct HeaderPolicy {
+	/// Only these are forwarded. Empty forwards everything but `drop`
+	pub allow: Vec<String>,
+	/// Never forwarded
+	pub drop: Vec<String>,
+	pub referer: RefererAction,
+	pub client_ip: ClientIpAction,
+	/// The headers `client_ip` applies to
+	pub client_ip_headers: Vec<String>,
+}
+
+impl Default for HeaderPolicy {
+	fn default() -> Self {
+		let names = |names: &[&str]| names.iter().map(ToString::to_string).collect();
+		Self {
+			allow: Vec::new(),
+			drop: names(&["cookie", "authorization", "proxy-authorization"]),
+			referer: RefererAction::default(),
+			client_ip: ClientIpAction::default(),
+			// The ones Umami looks for the client's address in
+			client_ip_headers: names(&[
+				"x-forwarded-for",
+				"x-real-ip",
+				"forwarded",
+				"x-forwarded",
+				"x-client-ip",
+				"x-cluster-client-ip",
+				"true-client-ip",
+				"cf-connecting-ip",
+				"do-connecting-ip",
+				"fastly-client-ip",
+				"x-appengine-user-ip",
+			]),
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RefererAction {
+	Keep,
+	/// Like a `url` field in the event
+	#[default]
+	Redact,
+	Drop,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientIpAction {
+	Keep,
+	/// IPv4 addresses to their /24, IPv6 to their /48. Anything that isn't an address is dropped
+	#[default]
+	Truncate,
+	Drop,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+/// Tweaks on top of the `isbot` default list
+pub struct BotPolicy {
+	/// Respond 403 to known bots
+	pub block: bool,
+	/// Extra user-agent regexes to treat as bots
+	pub extra_patterns: Vec<String>,
+	/// Patterns from the default list that should be let through
+	pub allowed_patterns: Vec<String>,
+}
+
+impl Default for BotPolicy {
+	fn default() -> Self {
+		Self {
+			block: true,
+			extra_patterns: Vec::new(),
+			allowed_patterns: Vec::new(),
+		}
+	}
+}
+
+impl BotPolicy {
+	pub fn bots(&self) -> isbot::Bots {
+		let mut bots = isbot::Bots::default();
+		let extra: Vec<&str> = self.extra_patterns.iter().map(String::as_str).collect();
+		let allowed: Vec<&str> = self.allowed_patterns.iter().map(String::as_str).collect();
+		bots.append(&extra);
+		bots.remove(&allowed);
+		bots
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct K8s {
+	/// Watch nais `Application`s to annotate events with app info
+	pub enabled: bool,
+	pub label_selector: String,
+	pub cache_capacity: usize,
+}
+
+impl Default for K8s {
+	fn default() -> Self {
+		Self {
+			enabled: true,
+			label_selector: "app,team".into(),
+			cache_capacity: 2000,
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+	pub field: String,
+	pub reason: String,
+}
+
+impl Violation {
+	pub fn new(field: impl Into<String>, reason: impl Into<String>) -> Self {
+		Self {
+			field: field.into(),
+			reason: reason.into(),
+		}
+	}
+}
+
+impl Display for Violation {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write!(f, "'{}': {}", self.field, self.reason)
+	}
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+	Read {
+		path: PathBuf,
+		source: std::io::Error,
+	},
+	Parse {
+		path: PathBuf,
+		source: serde_yaml::Error,
+	},
+	Invalid(Vec<Violation>),
+}
+
+impl Display for ConfigError {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		match self {
+			Self::Read { path, source } => {
+				write!(f, "unable to read {}: {source}", path.display())
+			},
+			Self::Parse { path, source } => {
+				write!(f, "unable to parse {}: {source}", path.display())
+			},
+			Self::Invalid(violations) => {
+				write!(f, "{} invalid setting(s):", violations.len())?;
+				for violation in violations {
+					write!(f, "\n  - {violation}")?;
+				}
+				Ok(())
+			},
+		}
+	}
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+	/// Reads the config file (if any), applies `UMAMI_PROXY__*` and then `UMAMI_*` env overrides
+	/// and validates the result
+	pub fn load() -> Result<Self, ConfigError> {
+		let (conf, tree) = match config_path() {
+			Some(path) => {
+				let contents = read(&path)?;
+				let parse = |source| ConfigError::Parse {
+					path: path.clone(),
+					source,
+				};
+				(
+					serde_yaml::from_str(&contents).map_err(parse)?,
+					serde_yaml::from_str(&contents).map_err(parse)?,
+				)
+			},
+			None => (Self::default(), serde_yaml::Value::Null),
+		};
+		let (mut conf, mut violations) = conf.with_overrides(tree, env::vars());
+		violations.extend(conf.apply_env_overrides());
+		violations.extend(conf.validate());
+		if violations.is_empty() {
+			Ok(conf)
+		} else {
+			Err(ConfigError::Invalid(violations))
+		}
+	}
+
+	pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+		serde_yaml::from_str(&read(path)?).map_err(|source| ConfigError::Parse {
+			path: path.to_path_buf(),
+			source,
+		})
+	}
+
+	/// Applies the `OVERRIDE_ENV_PREFIX` ones of `vars` to `tree`, the file this was parsed from.
+	/// One that doesn't fit is a violation named after its env var, and is left out
+	fn with_overrides(
+		self,
+		mut tree: serde_yaml::Value,
+		vars: impl IntoIterator<Item = (String, String)>,
+	) -> (Self, Vec<Violation>) {
+		let mut overrides: Vec<(String, String)> = vars
+			.into_iter()
+			.filter(|(name, _)| name.starts_with(OVERRIDE_ENV_PREFIX))
+			.collect();
+		overrides.sort();
+		let mut conf = self;
+		let mut violations = Vec::new();
+		for (name, value) in overrides {
+			let path: Vec<String> = name[OVERRIDE_ENV_PREFIX.len()..]
+				.split("__")
+				.map(str::to_lowercase)
+				.collect();
+			if path.iter().any(String::is_empty) {
+				violations.push(Violation::new(name, "has an empty part in its path"));
+				continue;
+			}
+			// Not everything is valid YAML, `[` for one, but it can still be a string
+			let value = serde_yaml::from_str(&value).unwrap_or(serde_yaml::Value::String(value));
+			let mut candidate = tree.clone();
+			set(&mut candidate, &path, value);
+			match serde_yaml::from_value(candidate.clone()) {
+				Ok(overridden) => {
+					conf = overridden;
+					tree = candidate;
+				},
+				Err(e) => violations.push(Violation::new(name, e.to_string())),
+			}
+		}
+		(conf, violations)
+	}
+
+	/// The env vars we've always deployed with win over the file and `UMAMI_PROXY__*`
+	fn apply_env_overrides(&mut self) -> Vec<Violation> {
+		let mut violations = Vec::new();
+		if let Ok(host) = env::var("UMAMI_HOST") {
+			self.upstream.host = host;
+		}
+		if let Ok(sni) = env::var("UMAMI_SNI") {
+			self.upstream.sni = Some(sni);
+		}
+		if let Ok(port) = env::var("UMAMI_PORT") {
+			match port.parse() {
+				Ok(port) => self.upstream.port = port,
+				Err(e) => violations.push(Violation::new("UMAMI_PORT", e.to_string())),
+			}
+		}
+		if let Ok(path) = env::var("UMAMI_PATH") {
+			self.upstream.path = Some(path);
+		}
+		violations
+	}
+
+	/// The upstream a route points at, or the default one
+	pub fn upstream(&self, name: Option<&str>) -> &Upstream {
+		name.and_then(|name| self.upstreams.get(name))
+			.unwrap_or(&self.upstream)
+	}
+
+	pub fn validate(&self) -> Vec<Violation> {
+		let mut violations = Vec::new();
+
+		if self.upstream.host.is_empty() {
+			violations.push(Violation::new(
+				"upstream.host",
+				"must be set (or set UMAMI_HOST)",
+			));
+		}
+		self.upstream.validate("upstream", &mut violations);
+		for (name, upstream) in &self.upstreams {
+			let field = format!("upstreams.{name}");
+			if upstream.host.is_empty() {
+				violations.push(Violation::new(format!("{field}.host"), "must be set"));
+			}
+			upstream.validate(&field, &mut violations);
+		}
+
+		for (i, route) in self.routes.iter().enumerate() {
+			if !self.upstreams.contains_key(&route.upstream) {
+				violations.push(Violation::new(
+					format!("routes[{i}].upstream"),
+					format!("'{}' is not one of `upstreams`", route.upstream),
+				));
+			}
+			if route.origins.is_empty() && route.path_prefixes.is_empty() && !route.needs_body() {
+				violations.push(Violation::new(
+					format!("routes[{i}]"),
+					"needs at least one of origins, path_prefixes, website_ids or namespaces",
+				));
+			}
+		}
+
+		if self.dns.refresh_secs == 0 {
+			violations.push(Violation::new("dns.refresh_secs", "must be non-zero"));
+		}
+
+		if let Some(path) = &self.health_check.path {
+			if !path.starts_with('/') {
+				violations.push(Violation::new("health_check.path", "must start with '/'"));
+			} else if let Err(e) = path.parse::<http::Uri>() {
+				// The health check request is built from it
+				violations.push(Violation::new(
+					"health_check.path",
+					format!("is not a valid request path: {e}"),
+				));
+			}
+		}
+		for (field, value) in [
+			(
+				"health_check.interval_secs",
+				self.health_check.interval_secs,
+			),
+			(
+				"health_check.unhealthy_after",
+				self.health_check.unhealthy_after as u64,
+			),
+			(
+				"health_check.healthy_after",
+				self.health_check.healthy_after as u64,
+			),
+			(
+				"circuit_breaker.failure_threshold",
+				u64::from(self.circuit_breaker.failure_threshold),
+			),
+			("circuit_breaker.open_secs", self.circuit_breaker.open_secs),
+			(
+				"redaction.pseudonymize.rotation_hours",
+				self.redaction.pseudonymize.rotation_hours,
+			),
+		] {
+			if value == 0 {
+				violations.push(Violation::new(field, "must be non-zero"));
+			}
+		}
+
+		if self
+			.redaction
+			.shadow
+			.as_ref()
+			.is_some_and(|s| s.log_every == 0)
+		{
+			violations.push(Violation::new(
+				"redaction.shadow.log_every",
+				"must be non-zero",
+			));
+		}
+
+		let pseudonymize = &self.redaction.pseudonymize;
+		if !pseudonymize.labels.is_empty() && pseudonymize.key_file.is_none() {
+			violations.push(Violation::new(
+				"redaction.pseudonymize.key_file",
+				"is needed to pseudonymize labels",
+			));
+		}
+		if pseudonymize.rotation_hours.checked_mul(60 * 60).is_none() {
+			violations.push(Violation::new(
+				"redaction.pseudonymize.rotation_hours",
+				"is too long to count in seconds",
+			));
+		}
+
+		for (service, listeners) in [
+			("listen.proxy", &self.listen.proxy),
+			("listen.probes", &self.listen.probes),
+			("listen.metrics", &self.listen.metrics),
+		] {
+			if listeners.is_empty() {
+				violations.push(Violation::new(service, "needs at least one listener"));
+			}
+			for (i, listener) in listeners.iter().enumerate() {
+				let field = format!("{service}[{i}]");
+				match (&listener.address, &listener.unix) {
+					(Some(addr), None) => {
+						if let Err(e) = addr.parse::<SocketAddr>() {
+							violations.push(Violation::new(field, format!("'{addr}' {e}")));
+						}
+					},
+					(None, Some(_)) if listener.tls.is_some() => {
+						violations.push(Violation::new(field, "tls needs a TCP address"));
+					},
+					(None, Some(_)) => {},
+					_ => violations.push(Violation::new(
+						field,
+						"needs exactly one of address and unix",
+					)),
+				}
+			}
+		}
+
+		// The 9 character "TRUNCATED" marker has to fit inside the limit
+		if self.limits.max_field_length <= 9 {
+			violations.push(Violation::new(
+				"limits.max_field_length",
+				"must be longer than the truncation marker (9 characters)",
+			));
+		}
+
+		for pattern in self
+			.bots
+			.extra_patterns
+			.iter()
+			.chain(&self.bots.allowed_patterns)
+		{
+			if let Err(e) = regex::Regex::new(pattern) {
+				violations.push(Violation::new("bots", format!("'{pattern}': {e}")));
+			}
+		}
+
+		if self.k8s.cache_capacity == 0 {
+			violations.push(Violation::new("k8s.cache_capacity", "must be non-zero"));
+		}
+
+		violations
+	}
+}
+
+fn read(path: &Path) -> Result<String, ConfigError> {
+	fs::read_to_string(path).map_err(|source| ConfigError::Read {
+		path: path.to_path_buf(),
+		source,
+	})
+}
+
+/// Puts `value` at `path` in `tree`, turning whatever is in the way into a mapping
+fn set(tree: &mut serde_yaml::Value, path: &[String], value: serde_yaml::Value) {
+	let Some((key, rest)) = path.split_first() else {
+		*tree = value;
+		return;
+	};
+	if !tree.is_mapping() {
+		*tree = serde_yaml::Value::Mapping(serde_yaml::Mapping::new());
+	}
+	let serde_yaml::Value::Mapping(mapping) = tree else {
+		unreachable!("It was just made a mapping");
+	};
+	let entry = mapping
+		.entry(serde_yaml::Value::String(key.clone()))
+		.or_insert(serde_yaml::Value::Null);
+	set(entry, rest, value);
+}
+
+pub fn config_path() -> Option<PathBuf> {
+	match env::var(CONFIG_PATH_ENV) {
+		Ok(path) => Some(PathBuf::from(path)),
+		Err(_) => Some(PathBuf::from(DEFAULT_CONFIG_PATH)).filter(|p| p.exists()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn test_parse_partial_config_uses_defaults() {
+		let conf: Config = serde_yaml::from_str(
+			r#"
+upstream:
+  host: umami.local
+  port: 3000
+limits:
+  max_field_length: 1000
+redaction:
+  skip_keys: [api_key, website]
+"#,
+		)
+		.unwrap();
+
+		assert_eq!(conf.upstream.host, "umami.local");
+		assert_eq!(conf.upstream.port, 3000);
+		assert_eq!(conf.limits.max_field_length, 1000);
+		assert_eq!(
+			conf.redaction.skip_keys,
+			KeyList::exact(&["api_key", "website"])
+		);
+		assert_eq!(
+			conf.redaction.drop_keys,
+			Redaction::default().drop_keys,
+			"Lists that aren't mentioned keep their defaults"
+		);
+		assert_eq!(conf.listen.proxy, vec![Listener::tcp("0.0.0.0:6191")]);
+		assert!(conf.validate().is_empty());
+	}
+
+	#[test]
+	fn test_key_lists() {
+		let conf: Redaction = serde_yaml::from_str(
+			r#"
+skip_keys: [api_key]
+name_exclusion_keys:
+  match: glob
+  keys: ["lenke*"]
+websites:
+  c2f0a46d:
+    drop_keys:
+      match: case_insensitive
+      keys: [orgnr]
+"#,
+		)
+		.unwrap();
+		assert_eq!(conf.skip_keys, KeyList::exact(&["api_key"]));
+		assert_eq!(conf.name_exclusion_keys.matching, KeyMatch::Glob);
+		assert_eq!(
+			conf.websites["c2f0a46d"].drop_keys.matching,
+			KeyMatch::CaseInsensitive
+		);
+		assert!(conf.websites["c2f0a46d"].skip_keys.keys.is_empty());
+
+		let unknown = serde_yaml::from_str::<Redaction>("skip_keys: { match: regex, keys: [a] }");
+		assert!(unknown.is_err());
+	}
+
+	#[test]
+	fn test_shipped_config_file_parses() {
+		let conf = Config::from_file(Path::new(DEFAULT_CONFIG_PATH)).unwrap();
+		let violations = conf.validate();
+		// The shipped file leaves the host to UMAMI_HOST
+		assert_eq!(
+			violations,
+			vec![Violation::new(
+				"upstream.host",
+				"must be set (or set UMAMI_HOST)"
+			)]
+		);
+	}
+
+	#[test]
+	fn test_prefixed_env_overrides() {
+		let file = "limits:\n  max_field_length: 1000\nredaction:\n  redact_keys: true\n";
+		let conf: Config = serde_yaml::from_str(file).unwrap();
+		let vars = [
+			("UMAMI_PROXY__LIMITS__MAX_FIELD_LENGTH", "200"),
+			("UMAMI_PROXY__REDACTION__REDACT_KEYS", "false"),
+			("UMAMI_PROXY__K8S__LABEL_SELECTOR", "app"),
+			(
+				"UMAMI_PROXY__LISTEN__METRICS",
+				"[{ address: '0.0.0.0:9999' }]",
+			),
+			("UMAMI_PROXY__LIMITS__MAX_FIELD_LENGHT", "10"),
+			("UMAMI_PROXY__DNS__", "x"),
+			("UMAMI_HOST", "ignored here"),
+		]
+		.map(|(name, value)| (name.to_owned(), value.to_owned()));
+		let (conf, violations) = conf.with_overrides(serde_yaml::from_str(file).unwrap(), vars);
+
+		assert_eq!(conf.limits.max_field_length, 200);
+		assert!(!conf.redaction.redact_keys);
+		assert_eq!(conf.k8s.label_selector, "app");
+		assert_eq!(conf.listen.metrics, vec![Listener::tcp("0.0.0.0:9999")]);
+		assert_eq!(conf.upstream.host, "");
+		let fields: Vec<&str> = violations.iter().map(|v| v.field.as_str()).collect();
+		assert_eq!(
+			fields,
+			vec![
+				"UMAMI_PROXY__DNS__",
+				"UMAMI_PROXY__LIMITS__MAX_FIELD_LENGHT"
+			]
+		);
+
+		let (conf, violations) = Config::default().with_overrides(
+			serde_yaml::Value::Null,
+			[("UMAMI_PROXY__UPSTREAM__PORT".into(), "3000".into())],
+		);
+		assert!(violations.is_empty());
+		assert_eq!(conf.upstream.port, 3000);
+	}
+
+	#[test]
+	fn test_unknown_fields_are_rejected() {
+		let result = serde_yaml::from_str::<Config>("upstream:\n  hots: umami.local\n");
+		assert!(result.is_err());
+	}
+
+	#[test]
+	fn test_routes_must_point_at_named_upstreams() {
+		let conf: Config = serde_yaml::from_str(
+			r#"
+upstream:
+  host: umami.local
+upstreams:
+  beta:
+    host: umami-beta.local
+routes:
+  - upstream: beta
+    namespaces: [team-canary]
+  - upstream: internal
+    origins: ["*.intern.nav.no"]
+  - upstream: beta
+"#,
+		)
+		.unwrap();
+
+		let fields: Vec<String> = conf.validate().into_iter().map(|v| v.field).collect();
+		assert_eq!(fields, vec!["routes[1].upstream", "routes[2]"]);
+		assert_eq!(conf.upstream(Some("beta")).host, "umami-beta.local");
+		assert_eq!(conf.upstream(None).host, "umami.local");
+	}
+
+	#[test]
+	fn test_validate_collects_all_violations() {
+		let mut conf = Config::default();
+		conf.upstream.path = Some("no-leading-slash".into());
+		conf.listen.metrics = vec![Listener::tcp("not an address")];
+		conf.listen.probes.push(Listener {
+			unix: Some("/run/probes.sock".into()),
+			..Listener::tcp("127.0.0.1:6970")
+		});
+		conf.limits.max_field_length = 3;
+		conf.bots.extra_patterns = vec!["(unclosed".into()];
+		conf.health_check.path = Some("/health check".into());
+		conf.redaction.pseudonymize.rotation_hours = u64::MAX;
+
+		let fields: Vec<String> = conf.validate().into_iter().map(|v| v.field).collect();
+		assert_eq!(
+			fields,
+			vec![
+				"upstream.host",
+				"upstream.path",
+				"health_check.path",
+				"redaction.pseudonymize.rotation_hours",
+				"listen.probes[1]",
+				"listen.metrics[0]",
+				"limits.max_field_length",
+				"bots",
+			]
+		);
+	}
 }
@@ -7,6 +7,8 @@ pub enum UmamiProxyError {
 	RequestContainsInvalidJson,
 	JsonCoParseError,
 	NoMatchingPeer,
+	UpstreamUnresolved,
+	CircuitOpen,
 	// This one matches the pingora::Error::Custom(string) exactly
 	PrematureBodyEnd,
 	FieldTooLong,
@@ -1,12 +1,25 @@
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use pingora::{
 	prelude::HttpPeer,
 	proxy::{ProxyHttp, Session},
-	Result,
+	Error, ErrorType, Result,
 };
 use tracing::trace;
 
-pub struct Probes;
+use crate::proxy::Settings;
+
+pub struct Probes {
+	settings: Arc<ArcSwap<Settings>>,
+}
+
+impl Probes {
+	pub const fn new(settings: Arc<ArcSwap<Settings>>) -> Self {
+		Self { settings }
+	}
+}
 
 #[derive(Debug)]
 pub struct Ctx {}
@@ -31,19 +44,28 @@ impl ProxyHttp for Probes {
 	where
 		Self::CTX: Send + Sync,
 	{
-		if session
+		let path = session
 			.downstream_session
 			.req_header()
 			.as_owned_parts()
 			.uri
 			.path()
-			.contains("is_alive")
+			.to_owned();
+		if path.contains("is_alive")
 		// this also matches is_aliveeeeeeeee etc
 		{
 			session.respond_error(200).await?; // Can we respond without saying error?
 			trace!("is_alive: 200");
 			return Ok(true);
 		}
+		// Ready when the default upstream has a healthy address and its breaker isn't open
+		if path.contains("is_ready") {
+			let ready = self.settings.load().balancer(None).is_ready();
+			let status = if ready { 200 } else { 503 };
+			session.respond_error(status).await?;
+			trace!("is_ready: {status}");
+			return Ok(true);
+		}
 		session.respond_error(404).await?;
 		trace!("fail: 404");
 
@@ -56,9 +78,10 @@ impl ProxyHttp for Probes {
 		_session: &mut Session,
 		_ctx: &mut Self::CTX,
 	) -> Result<Box<HttpPeer>> {
-		// Panic is not the best implementation here.
-		// The correct implementation would be to have the readyness probe go all the way down here and
-		// check that we can connect to the upstream. In case of ip change
-		panic!(); // We don't need an implementation here as going further from request_filter is a bug in this proxy
+		// Going further than request_filter is a bug in this proxy, readiness is answered there
+		Error::e_explain(
+			ErrorType::InternalError,
+			"probes never proxy, request_filter answers everything",
+		)
 	}
 }
@@ -1,5 +1,7 @@
-use crate::metrics::INGRESS_COUNT;
+use crate::metrics::{APP_REDACTION_POLICIES, INGRESS_COUNT};
+use crate::proxy::AppPolicy;
 use futures::TryStreamExt;
+use k8s_openapi::api::core::v1::ConfigMap;
 use kube::{
 	api::{Api, ListParams},
 	runtime::{watcher, WatchStreamExt},
@@ -10,6 +12,13 @@ pub mod cache;
 use kube::CustomResource;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// An inline redaction policy, see `AppPolicy::parse`
+const REDACTION_ANNOTATION: &str = "umami-proxy.nav.no/redaction";
+/// Names a ConfigMap in the app's namespace that has the policy under `REDACTION_CONFIGMAP_KEY`
+const REDACTION_CONFIGMAP_ANNOTATION: &str = "umami-proxy.nav.no/redaction-configmap";
+const REDACTION_CONFIGMAP_KEY: &str = "redaction.yaml";
 
 #[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
 #[kube(
@@ -30,7 +39,9 @@ pub async fn populate_cache() -> Result<(), Box<dyn std::error::Error>> {
 		.list(&ListParams::default())
 		.await?;
 	for app in app_list {
-		if let Some(app_info) = application_to_app_info(&app) {
+		if let Some(mut app_info) = application_to_app_info(&app) {
+			(app_info.redaction, app_info.redaction_configmap) =
+				redaction_policy(&client, &app).await;
 			warn!("added an application: {:?}", app_info);
 			cache::insert_into_cache(app_info.ingress.clone(), app_info);
 		}
@@ -46,27 +57,76 @@ pub async fn populate_cache() -> Result<(), Box<dyn std::error::Error>> {
 	Ok(())
 }
 
-pub async fn run_watcher() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn run_watcher(label_selector: &str) -> Result<(), Box<dyn std::error::Error>> {
 	info!("Started application watcher");
+	let client = Client::try_default().await?;
 	watcher(
-		Api::<Application>::all(Client::try_default().await?),
-		watcher::Config::default().labels("app,team"),
+		Api::<Application>::all(client.clone()),
+		watcher::Config::default().labels(label_selector),
 	)
 	.applied_objects()
 	.default_backoff()
-	.try_for_each(move |app| async move {
-		if let Some(app_info) = application_to_app_info(&app) {
-			info!("New Application found, {}", app_info.app_name);
-			INGRESS_COUNT.inc();
-			cache::insert_into_cache(app_info.ingress.clone(), app_info);
+	.try_for_each(move |app| {
+		let client = client.clone();
+		async move {
+			if let Some(mut app_info) = application_to_app_info(&app) {
+				(app_info.redaction, app_info.redaction_configmap) =
+					redaction_policy(&client, &app).await;
+				info!("New Application found, {}", app_info.app_name);
+				INGRESS_COUNT.inc();
+				cache::insert_into_cache(app_info.ingress.clone(), app_info);
+			}
+			Ok(())
 		}
-		Ok(())
 	})
 	.await?;
 
 	Ok(())
 }
 
+/// Keeps the policies of apps that use `umami-proxy.nav.no/redaction-configmap` up to date with
+/// their ConfigMaps. A deleted ConfigMap leaves the app with the shared redaction
+pub async fn run_configmap_watcher() -> Result<(), Box<dyn std::error::Error>> {
+	info!("Started redaction ConfigMap watcher");
+	let client = Client::try_default().await?;
+	watcher(Api::<ConfigMap>::all(client), watcher::Config::default())
+		.default_backoff()
+		.try_for_each(|event| async move {
+			match event {
+				watcher::Event::Apply(configmap) | watcher::Event::InitApply(configmap) => {
+					configmap_changed(&configmap, false);
+				},
+				watcher::Event::Delete(configmap) => configmap_changed(&configmap, true),
+				watcher::Event::Init | watcher::Event::InitDone => {},
+			}
+			Ok(())
+		})
+		.await?;
+
+	Ok(())
+}
+
+fn configmap_changed(configmap: &ConfigMap, deleted: bool) {
+	let (Some(namespace), Some(name)) = (
+		configmap.metadata.namespace.as_deref(),
+		configmap.metadata.name.as_deref(),
+	) else {
+		return;
+	};
+	// Most ConfigMaps have nothing to do with redaction
+	if !cache::uses_configmap(namespace, name) {
+		return;
+	}
+	let source = if deleted {
+		Err(format!("ConfigMap {namespace}/{name} was deleted"))
+	} else {
+		configmap_policy(configmap, namespace, name)
+	};
+	let policy = load_policy(&format!("ConfigMap {namespace}/{name}"), source);
+	let apps = cache::set_configmap_redaction(namespace, name, policy);
+	info!("ConfigMap {namespace}/{name} changed, updated the redaction of {apps} apps");
+}
+
 fn application_to_app_info(application: &Application) -> Option<cache::AppInfo> {
 	let app = application.clone();
 	let ingresses = &app.spec.ingresses?;
@@ -93,5 +153,63 @@ fn application_to_app_info(application: &Application) -> Option<cache::AppInfo>
 		namespace: namespace.into(),
 		ingress: ingress_url.to_string(),
 		creation_timestamp: creation_timestamp.into(),
+		redaction: None,
+		redaction_configmap: None,
 	})
 }
+
+/// The app's redaction policy, from its annotation or the ConfigMap it names, and the name of
+/// that ConfigMap
+async fn redaction_policy(
+	client: &Client,
+	app: &Application,
+) -> (Option<Arc<AppPolicy>>, Option<String>) {
+	let Some(annotations) = app.metadata.annotations.as_ref() else {
+		return (None, None);
+	};
+	let app_name = app.metadata.name.as_deref().unwrap_or("unknown app name");
+	if let Some(inline) = annotations.get(REDACTION_ANNOTATION) {
+		return (load_policy(app_name, Ok(inline.clone())), None);
+	}
+	let (Some(configmap), Some(namespace)) = (
+		annotations.get(REDACTION_CONFIGMAP_ANNOTATION),
+		app.metadata.namespace.as_deref(),
+	) else {
+		return (None, None);
+	};
+	let source = read_configmap(client, namespace, configmap).await;
+	(load_policy(app_name, source), Some(configmap.clone()))
+}
+
+/// A policy that doesn't load is logged and the app gets the shared redaction
+fn load_policy(app_name: &str, source: Result<String, String>) -> Option<Arc<AppPolicy>> {
+	match source.and_then(|source| AppPolicy::parse(&source).map_err(|e| e.join(", "))) {
+		Ok(policy) => {
+			APP_REDACTION_POLICIES.with_label_values(&["loaded"]).inc();
+			Some(Arc::new(policy))
+		},
+		Err(e) => {
+			APP_REDACTION_POLICIES
+				.with_label_values(&["rejected"])
+				.inc();
+			warn!("redaction policy for {app_name} rejected, using the shared one: {e}");
+			None
+		},
+	}
+}
+
+async fn read_configmap(client: &Client, namespace: &str, name: &str) -> Result<String, String> {
+	let configmap = Api::<ConfigMap>::namespaced(client.clone(), namespace)
+		.get(name)
+		.await
+		.map_err(|e| format!("ConfigMap {namespace}/{name}: {e}"))?;
+	configmap_policy(&configmap, namespace, name)
+}
+
+fn configmap_policy(configmap: &ConfigMap, namespace: &str, name: &str) -> Result<String, String> {
+	configmap
+		.data
+		.as_ref()
+		.and_then(|data| data.get(REDACTION_CONFIGMAP_KEY).cloned())
+		.ok_or_else(|| format!("ConfigMap {namespace}/{name} has no {REDACTION_CONFIGMAP_KEY}"))
+}
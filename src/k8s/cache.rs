@@ -5,6 +5,8 @@ use std::num::NonZeroUsize;
 use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 
+use crate::proxy::AppPolicy;
+
 pub static CACHE: Lazy<Arc<Mutex<LruCache<String, AppInfo>>>> = Lazy::new(|| {
 	Arc::new(Mutex::new(LruCache::new(
 		NonZeroUsize::new(2000).expect("cache has positive capacity"),
@@ -26,6 +28,15 @@ pub struct AppInfo {
 	pub namespace: String,
 	pub ingress: String,
 	pub creation_timestamp: String,
+	/// From the app's redaction annotation, `None` gets the shared redaction
+	pub redaction: Option<Arc<AppPolicy>>,
+	/// The ConfigMap `redaction` was read from, so that changes to it are picked up
+	pub redaction_configmap: Option<String>,
+}
+
+/// Only shrinks/grows the LRU, the prefix trie keeps whatever it has
+pub fn resize(capacity: NonZeroUsize) {
+	CACHE.lock().expect("Failed to lock cache").resize(capacity);
 }
 
 pub fn insert_into_cache(key: String, value: AppInfo) {
@@ -40,6 +51,37 @@ pub fn insert_into_cache(key: String, value: AppInfo) {
 		.insert(key.clone().bytes(), key);
 }
 
+/// Whether any app's redaction comes from ConfigMap `namespace/name`
+pub fn uses_configmap(namespace: &str, name: &str) -> bool {
+	CACHE
+		.lock()
+		.expect("Failed to lock cache")
+		.iter()
+		.any(|(_, app)| reads_configmap(app, namespace, name))
+}
+
+/// Gives every app whose redaction comes from ConfigMap `namespace/name` the policy `redaction`,
+/// and returns how many there were
+pub fn set_configmap_redaction(
+	namespace: &str,
+	name: &str,
+	redaction: Option<Arc<AppPolicy>>,
+) -> usize {
+	let mut cache = CACHE.lock().expect("Failed to lock cache");
+	let mut updated = 0;
+	for (_, app) in cache.iter_mut() {
+		if reads_configmap(app, namespace, name) {
+			app.redaction = redaction.clone();
+			updated += 1;
+		}
+	}
+	updated
+}
+
+fn reads_configmap(app: &AppInfo, namespace: &str, name: &str) -> bool {
+	app.namespace == namespace && app.redaction_configmap.as_deref() == Some(name)
+}
+
 pub fn get_app_info_with_longest_prefix(key: &str) -> Option<AppInfo> {
 	if let Some(longest_prefix) = PREFIX_TRIE
 		.lock()
@@ -68,6 +110,8 @@ mod tests {
 			namespace: "test-namespace".to_string(),
 			ingress: "test-ingress".to_string(),
 			creation_timestamp: "2023-01-01T00:00:00Z".to_string(),
+			redaction: None,
+			redaction_configmap: None,
 		};
 
 		insert_into_cache(key.clone(), app_info.clone());
@@ -98,4 +142,40 @@ mod tests {
 			"Prefix-based retrieval should match inserted AppInfo"
 		);
 	}
+
+	#[test]
+	fn test_set_configmap_redaction() {
+		let app = |name: &str, namespace: &str, configmap: Option<&str>| AppInfo {
+			app_name: name.into(),
+			namespace: namespace.into(),
+			ingress: format!("https://{name}.configmap.test"),
+			creation_timestamp: "2023-01-01T00:00:00Z".into(),
+			redaction: None,
+			redaction_configmap: configmap.map(Into::into),
+		};
+		for app in [
+			app("a", "team-a", Some("redaction")),
+			app("b", "team-a", Some("redaction")),
+			app("c", "team-b", Some("redaction")),
+			app("d", "team-a", None),
+		] {
+			insert_into_cache(app.ingress.clone(), app);
+		}
+		assert!(uses_configmap("team-a", "redaction"));
+		assert!(!uses_configmap("team-a", "other"));
+
+		let policy = Arc::new(AppPolicy::parse("disabled_labels: [PROXY-IP]").unwrap());
+		assert_eq!(
+			set_configmap_redaction("team-a", "redaction", Some(policy)),
+			2
+		);
+		let has_policy = |name: &str| {
+			get_app_info_with_longest_prefix(&format!("https://{name}.configmap.test"))
+				.unwrap()
+				.redaction
+				.is_some()
+		};
+		assert!(has_policy("a") && has_policy("b"));
+		assert!(!has_policy("c") && !has_policy("d"));
+	}
 }
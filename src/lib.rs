@@ -0,0 +1,10 @@
+pub mod config;
+pub mod errors;
+pub mod health;
+pub mod k8s;
+pub mod listen;
+pub mod metrics;
+pub mod proxy;
+pub mod reload;
+pub mod trace;
+pub mod upstream;
@@ -0,0 +1,268 @@
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::listeners::tls::TlsSettings;
+use pingora::listeners::TlsAccept;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::services::listening::Service;
+use pingora::tls::ext;
+use pingora::tls::pkey::{PKey, Private};
+use pingora::tls::ssl::SslRef;
+use pingora::tls::x509::X509;
+use tokio::time;
+use tracing::{error, info};
+
+use crate::config::{ConfigError, Listener, Tls, Violation};
+use crate::metrics::CERT_RELOADS;
+use crate::reload::POLL_INTERVAL;
+
+/// Binds `service` to every listener in the list. TLS certs are appended to `certs`, so they can
+/// be handed to a `CertReloader`
+pub fn add_listeners<A>(
+	service: &mut Service<A>,
+	field: &str,
+	listeners: &[Listener],
+	certs: &mut Vec<Cert>,
+) -> Result<(), ConfigError> {
+	let mut violations = Vec::new();
+	for (i, listener) in listeners.iter().enumerate() {
+		let field = format!("{field}[{i}]");
+		match (&listener.address, &listener.unix, &listener.tls) {
+			(Some(addr), _, None) => service.add_tcp(addr),
+			(Some(addr), _, Some(tls)) => {
+				let added = Cert::load(tls).and_then(|cert| {
+					let settings = TlsSettings::with_callbacks(Box::new(cert.clone()))
+						.map_err(|e| e.to_string())?;
+					service.add_tls_with_settings(addr, None, settings);
+					certs.push(cert);
+					Ok(())
+				});
+				if let Err(e) = added {
+					violations.push(Violation::new(format!("{field}.tls"), e));
+				}
+			},
+			(None, Some(path), _) => match path.to_str() {
+				Some(path) => service.add_uds(path, None),
+				None => violations.push(Violation::new(field, "unix path must be UTF-8")),
+			},
+			// `Config::validate` rules this out
+			(None, None, _) => violations.push(Violation::new(field, "has no address")),
+		}
+	}
+	if violations.is_empty() {
+		Ok(())
+	} else {
+		Err(ConfigError::Invalid(violations))
+	}
+}
+
+struct Loaded {
+	chain: Vec<X509>,
+	key: PKey<Private>,
+	modified: Option<SystemTime>,
+}
+
+/// A cert/key pair handed out from pingora's certificate callback, so it can be swapped on a
+/// running listener
+#[derive(Clone)]
+pub struct Cert {
+	paths: Tls,
+	loaded: Arc<ArcSwap<Loaded>>,
+	/// Last mtime we tried, so a broken pair isn't re-read (and logged) every poll
+	tried: Arc<Mutex<Option<SystemTime>>>,
+}
+
+impl Cert {
+	pub fn load(paths: &Tls) -> Result<Self, String> {
+		let loaded = read(paths)?;
+		Ok(Self {
+			paths: paths.clone(),
+			tried: Arc::new(Mutex::new(loaded.modified)),
+			loaded: Arc::new(ArcSwap::from_pointee(loaded)),
+		})
+	}
+
+	fn reload_if_changed(&self) {
+		let modified = modified(&self.paths);
+		{
+			let mut tried = self.tried.lock().expect("Failed to lock cert mtime");
+			if *tried == modified {
+				return;
+			}
+			*tried = modified;
+		}
+		match read(&self.paths) {
+			Ok(loaded) => {
+				self.loaded.store(Arc::new(loaded));
+				CERT_RELOADS.with_label_values(&["success"]).inc();
+				info!("reloaded certificate {}", self.paths.cert.display());
+			},
+			Err(e) => {
+				CERT_RELOADS.with_label_values(&["rejected"]).inc();
+				error!("certificate reload rejected, keeping the current one: {e}");
+			},
+		}
+	}
+}
+
+#[async_trait]
+impl TlsAccept for Cert {
+	async fn certificate_callback(&self, ssl: &mut SslRef) {
+		let loaded = self.loaded.load();
+		// `read` never returns an empty chain
+		let (leaf, intermediates) = loaded
+			.chain
+			.split_first()
+			.expect("certificate chain is non-empty");
+		let result = ext::ssl_use_certificate(ssl, leaf)
+			.and_then(|()| ext::ssl_use_private_key(ssl, &loaded.key))
+			.and_then(|()| {
+				intermediates
+					.iter()
+					.try_for_each(|cert| ext::ssl_add_chain_cert(ssl, cert))
+			});
+		if let Err(e) = result {
+			error!("unable to use {}: {e}", self.paths.cert.display());
+		}
+	}
+}
+
+fn read(paths: &Tls) -> Result<Loaded, String> {
+	// Taken first, so a change landing while we read is picked up on the next poll
+	let modified = modified(paths);
+	let (chain, key) = read_pair(paths)?;
+	Ok(Loaded {
+		chain,
+		key,
+		modified,
+	})
+}
+
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+	fs::read(path).map_err(|e| format!("{}: {e}", path.display()))
+}
+
+/// Every certificate in a PEM file
+pub fn read_certs(path: &Path) -> Result<Vec<X509>, String> {
+	let certs =
+		X509::stack_from_pem(&read_pem(path)?).map_err(|e| format!("{}: {e}", path.display()))?;
+	if certs.is_empty() {
+		return Err(format!("{}: no certificate found", path.display()));
+	}
+	Ok(certs)
+}
+
+/// A certificate chain and the private key for its first certificate
+pub fn read_pair(paths: &Tls) -> Result<(Vec<X509>, PKey<Private>), String> {
+	let chain = read_certs(&paths.cert)?;
+	let key = PKey::private_key_from_pem(&read_pem(&paths.key)?)
+		.map_err(|e| format!("{}: {e}", paths.key.display()))?;
+	// Cert and key are usually replaced one at a time, don't use a half-rotated pair
+	let matches = chain[0]
+		.public_key()
+		.is_ok_and(|public_key| key.public_eq(&public_key));
+	if !matches {
+		return Err(format!(
+			"{} doesn't match {}",
+			paths.key.display(),
+			paths.cert.display()
+		));
+	}
+	Ok((chain, key))
+}
+
+fn modified(paths: &Tls) -> Option<SystemTime> {
+	let mtime = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+	mtime(&paths.cert).max(mtime(&paths.key))
+}
+
+/// Polls the TLS listeners' cert and key files, same as the config file
+pub struct CertReloader {
+	certs: Vec<Cert>,
+}
+
+impl CertReloader {
+	pub const fn new(certs: Vec<Cert>) -> Self {
+		Self { certs }
+	}
+}
+
+#[async_trait]
+impl BackgroundService for CertReloader {
+	async fn start(&self, mut shutdown: ShutdownWatch) {
+		let mut interval = time::interval(POLL_INTERVAL);
+		loop {
+			tokio::select! {
+				_ = shutdown.changed() => return,
+				_ = interval.tick() => {
+					for cert in &self.certs {
+						cert.reload_if_changed();
+					}
+				},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use openssl::asn1::Asn1Time;
+	use openssl::hash::MessageDigest;
+	use openssl::rsa::Rsa;
+	use openssl::x509::X509NameBuilder;
+	use std::path::PathBuf;
+
+	fn self_signed(key: &PKey<Private>) -> Vec<u8> {
+		let mut name = X509NameBuilder::new().unwrap();
+		name.append_entry_by_text("CN", "umami-proxy.local")
+			.unwrap();
+		let name = name.build();
+		let mut cert = X509::builder().unwrap();
+		cert.set_version(2).unwrap();
+		cert.set_subject_name(&name).unwrap();
+		cert.set_issuer_name(&name).unwrap();
+		cert.set_pubkey(key).unwrap();
+		cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+			.unwrap();
+		cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+			.unwrap();
+		cert.sign(key, MessageDigest::sha256()).unwrap();
+		cert.build().to_pem().unwrap()
+	}
+
+	fn write(dir: &Path, name: &str, contents: &[u8]) -> PathBuf {
+		let path = dir.join(name);
+		fs::write(&path, contents).unwrap();
+		path
+	}
+
+	#[test]
+	fn test_cert_must_match_key() {
+		let dir = std::env::temp_dir().join(format!("umami-proxy-tls-{}", std::process::id()));
+		fs::create_dir_all(&dir).unwrap();
+		let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+		let other_key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+
+		let tls = Tls {
+			cert: write(&dir, "cert.pem", &self_signed(&key)),
+			key: write(&dir, "key.pem", &key.private_key_to_pem_pkcs8().unwrap()),
+		};
+		assert!(Cert::load(&tls).is_ok());
+
+		write(
+			&dir,
+			"key.pem",
+			&other_key.private_key_to_pem_pkcs8().unwrap(),
+		);
+		let error = Cert::load(&tls).err().unwrap();
+		assert!(error.contains("doesn't match"), "{error}");
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -1,18 +1,27 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use pingora::services::background::background_service;
 use pingora::services::listening::Service;
 use pingora::{prelude::Opt, proxy as pingora_proxy, server::Server};
-use tracing::info;
-mod config;
-mod errors;
-mod health;
-mod k8s;
-mod metrics;
-mod proxy;
-mod trace;
+use tracing::{error, info};
+use umami_proxy::{config, health, k8s, listen, proxy, reload, trace, upstream};
 
 fn main() {
-	let conf = config::Config::new();
 	trace::init();
-	info!("started proxy{:#?}", &conf);
+	let settings = match config::Config::load().and_then(proxy::Settings::new) {
+		Ok(settings) => settings,
+		Err(e) => {
+			error!("invalid configuration, {e}");
+			std::process::exit(1);
+		},
+	};
+	info!("started proxy{:#?}", &settings.conf);
+	// Listeners and the k8s cache are set up once, reloads don't touch them
+	let conf = settings.conf.clone();
+	let settings = Arc::new(ArcSwap::from_pointee(settings));
+
 	let mut umami_proxy = Server::new(Some(Opt {
 		upgrade: false,
 		daemon: false,
@@ -24,19 +33,60 @@ fn main() {
 
 	umami_proxy.bootstrap();
 
-	let proxy = proxy::Umami::new(conf.clone(), isbot::Bots::default());
+	if let Some(capacity) = NonZeroUsize::new(conf.k8s.cache_capacity) {
+		k8s::cache::resize(capacity);
+	}
+
+	let proxy = proxy::Umami::new(settings.clone());
+	let probes = health::Probes::new(settings.clone());
+	let monitor = background_service(
+		"upstream monitor",
+		upstream::UpstreamMonitor::new(settings.clone()),
+	);
+	let reloader = background_service("config reloader", reload::ConfigReloader::new(settings));
 
-	let mut probe_instance =
-		pingora_proxy::http_proxy_service(&umami_proxy.configuration, health::Probes {});
+	let mut probe_instance = pingora_proxy::http_proxy_service(&umami_proxy.configuration, probes);
 	let mut proxy_instance = pingora_proxy::http_proxy_service(&umami_proxy.configuration, proxy);
 
 	// All services get allocated threads: from the config. Someone should upstream more granularity on that
 	let mut prome_service_http = Service::prometheus_http_service();
-	prome_service_http.add_tcp("0.0.0.0:9090");
-	probe_instance.add_tcp("0.0.0.0:6969");
-	proxy_instance.add_tcp("0.0.0.0:6191");
+	let mut certs = Vec::new();
+	let listening = listen::add_listeners(
+		&mut proxy_instance,
+		"listen.proxy",
+		&conf.listen.proxy,
+		&mut certs,
+	)
+	.and_then(|()| {
+		listen::add_listeners(
+			&mut probe_instance,
+			"listen.probes",
+			&conf.listen.probes,
+			&mut certs,
+		)
+	})
+	.and_then(|()| {
+		listen::add_listeners(
+			&mut prome_service_http,
+			"listen.metrics",
+			&conf.listen.metrics,
+			&mut certs,
+		)
+	});
+	if let Err(e) = listening {
+		error!("invalid configuration, {e}");
+		std::process::exit(1);
+	}
+	if !certs.is_empty() {
+		umami_proxy.add_service(background_service(
+			"certificate reloader",
+			listen::CertReloader::new(certs),
+		));
+	}
 	umami_proxy.add_service(probe_instance);
 	umami_proxy.add_service(proxy_instance);
 	umami_proxy.add_service(prome_service_http);
+	umami_proxy.add_service(monitor);
+	umami_proxy.add_service(reloader);
 	umami_proxy.run_forever();
 }
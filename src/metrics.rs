@@ -1,6 +1,6 @@
 use once_cell::sync::Lazy;
 
-use prometheus::{register_gauge, Gauge, IntCounterVec};
+use prometheus::{register_gauge, register_int_gauge_vec, Gauge, IntCounterVec, IntGaugeVec};
 use prometheus::{register_int_counter, register_int_counter_vec, IntCounter};
 
 pub static INCOMING_REQUESTS: Lazy<IntCounter> =
@@ -22,3 +22,70 @@ pub static INVALID_PEER: Lazy<IntCounter> =
 
 pub static INGRESS_COUNT: Lazy<Gauge> =
 	Lazy::new(|| register_gauge!("ingress_count", "Number of ingresses in the cache").unwrap());
+
+pub static CONFIG_RELOADS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!("config_reloads_total", "config reloads", &["result"]).unwrap()
+});
+
+pub static ROUTED_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!("routed_requests_total", "routed requests", &["upstream"]).unwrap()
+});
+
+pub static UPSTREAM_RESOLUTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!(
+		"upstream_resolutions_total",
+		"upstream dns resolutions",
+		&["upstream", "result"]
+	)
+	.unwrap()
+});
+
+/// 0 closed, 1 open, 2 half-open
+pub static CIRCUIT_BREAKER_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+	register_int_gauge_vec!(
+		"circuit_breaker_state",
+		"circuit breaker state per upstream",
+		&["upstream"]
+	)
+	.unwrap()
+});
+
+pub static CIRCUIT_BREAKER_TRIPS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!(
+		"circuit_breaker_trips_total",
+		"circuit breaker trips",
+		&["upstream"]
+	)
+	.unwrap()
+});
+
+pub static CERT_RELOADS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!("cert_reloads_total", "tls certificate reloads", &["result"]).unwrap()
+});
+
+pub static APP_REDACTION_POLICIES: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!(
+		"app_redaction_policies_total",
+		"per-app redaction policies read from k8s",
+		&["result"]
+	)
+	.unwrap()
+});
+
+pub static SHADOW_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!(
+		"shadow_redaction_events_total",
+		"events redacted by the shadow rule set, by whether the output differed",
+		&["app", "result"]
+	)
+	.unwrap()
+});
+
+pub static SHADOW_DIFFERENCES: Lazy<IntCounterVec> = Lazy::new(|| {
+	register_int_counter_vec!(
+		"shadow_redaction_differences_total",
+		"fields the shadow rule set redacted differently",
+		&["app", "label"]
+	)
+	.unwrap()
+});
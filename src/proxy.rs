@@ -1,44 +1,144 @@
-use std::collections::HashMap;
-use std::net::ToSocketAddrs;
+use std::collections::{BTreeMap, HashMap};
 use std::str::FromStr;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use async_trait::async_trait;
 use bytes::Bytes;
 use pingora::http::ResponseHeader;
-use pingora::ErrorType as ErrType;
 use pingora::{
 	http::RequestHeader,
 	prelude::HttpPeer,
 	proxy::{ProxyHttp, Session},
 	Error, OrErr, Result,
 };
+use pingora::{ErrorSource, ErrorType as ErrType};
 use serde_json::{json, Value};
 use tokio::time;
 use tracing::{error, info, trace, warn};
 mod annotate;
+mod checksum;
+mod encoding;
+mod fields;
+mod fnr;
+mod headers;
+mod keys;
+mod names;
 mod privacy;
+mod pseudonym;
 mod redact;
+mod route;
+mod shadow;
+mod url;
 mod validate;
 use isbot::Bots;
+pub use redact::{AppPolicy, Redactor};
 
-use crate::config::Config;
+use crate::config::{Config, ConfigError, Route, Upstream, Violation};
 use crate::errors::{ErrorDescription, UmamiProxyError};
 use crate::k8s::{
 	self,
 	cache::{self, INITIALIZED},
 };
 use crate::metrics::{
-	HANDLED_REQUESTS, INCOMING_REQUESTS, INVALID_PEER, PROXY_ERRORS, UPSTREAM_PEER,
+	HANDLED_REQUESTS, INCOMING_REQUESTS, INVALID_PEER, PROXY_ERRORS, ROUTED_REQUESTS, UPSTREAM_PEER,
 };
-pub struct Umami {
+use crate::upstream::breaker::Permit;
+use crate::upstream::{Balancer, Lease};
+
+// pingora only replays a body read in `request_filter` if it fits in its retry buffer (64KiB)
+const PREFETCH_LIMIT: usize = 64 * 1024;
+
+/// Everything that can change on a config reload. Each request takes one snapshot in `new_ctx`
+/// and keeps it, so a reload never mixes old and new settings within a request
+#[derive(Debug)]
+pub struct Settings {
 	pub conf: Config,
-	pub bots: Bots,
+	bots: Bots,
+	redactor: redact::Redactor,
+	shadow: Option<shadow::Shadow>,
+	default_balancer: Arc<Balancer>,
+	balancers: BTreeMap<String, Arc<Balancer>>,
+}
+
+impl Settings {
+	pub fn new(conf: Config) -> Result<Self, ConfigError> {
+		Self::build(conf, None)
+	}
+
+	/// Like `new`, but upstreams whose config didn't change keep `previous`'s balancer, so their
+	/// breaker, address health and requests in flight survive the reload
+	pub fn reload(conf: Config, previous: &Self) -> Result<Self, ConfigError> {
+		Self::build(conf, Some(previous))
+	}
+
+	fn build(conf: Config, previous: Option<&Self>) -> Result<Self, ConfigError> {
+		let mut violations = Vec::new();
+		let redactor = redact::Redactor::new(&conf.redaction).map_err(|e| violations.extend(e));
+		let shadow = shadow::Shadow::new(&conf.redaction).map_err(|e| violations.extend(e));
+
+		// The balancers also depend on the health check and breaker settings
+		let previous = previous.filter(|previous| {
+			previous.conf.health_check == conf.health_check
+				&& previous.conf.circuit_breaker == conf.circuit_breaker
+		});
+		let mut balancer = |field: String, name: Option<&str>, upstream: &Upstream| {
+			let unchanged = previous.and_then(|previous| match name {
+				None => (previous.conf.upstream == *upstream).then_some(&previous.default_balancer),
+				Some(name) => previous
+					.conf
+					.upstreams
+					.get(name)
+					.filter(|previous| *previous == upstream)
+					.and(previous.balancers.get(name)),
+			});
+			if let Some(balancer) = unchanged {
+				return Ok(Arc::clone(balancer));
+			}
+			Balancer::new(name.unwrap_or("default"), upstream, &conf)
+				.map(Arc::new)
+				.map_err(|e| violations.push(Violation::new(format!("{field}.tls"), e)))
+		};
+		let default_balancer = balancer("upstream".into(), None, &conf.upstream);
+		let mut balancers = BTreeMap::new();
+		for (name, upstream) in &conf.upstreams {
+			if let Ok(named) = balancer(format!("upstreams.{name}"), Some(name), upstream) {
+				balancers.insert(name.clone(), named);
+			}
+		}
+
+		match (redactor, shadow, default_balancer) {
+			(Ok(redactor), Ok(shadow), Ok(default_balancer)) if violations.is_empty() => Ok(Self {
+				bots: conf.bots.bots(),
+				redactor,
+				shadow,
+				default_balancer,
+				balancers,
+				conf,
+			}),
+			_ => Err(ConfigError::Invalid(violations)),
+		}
+	}
+
+	/// Same lookup as `Config::upstream`
+	pub fn balancer(&self, name: Option<&str>) -> &Arc<Balancer> {
+		name.and_then(|name| self.balancers.get(name))
+			.unwrap_or(&self.default_balancer)
+	}
+
+	pub fn balancers(&self) -> impl Iterator<Item = &Arc<Balancer>> {
+		std::iter::once(&self.default_balancer).chain(self.balancers.values())
+	}
+}
+
+pub struct Umami {
+	pub settings: Arc<ArcSwap<Settings>>,
 }
 
 impl Umami {
-	pub const fn new(conf: Config, bots: Bots) -> Self {
-		Self { conf, bots }
+	pub const fn new(settings: Arc<ArcSwap<Settings>>) -> Self {
+		Self { settings }
 	}
 }
 
@@ -50,9 +150,16 @@ pub struct Location {
 
 #[derive(Debug)]
 pub struct Ctx {
+	settings: Arc<Settings>,
 	request_body_buffer: Vec<u8>,
 	location: Option<Location>,
 	ingress: String,
+	/// Named upstream picked by `routes`, `None` is the default upstream
+	upstream: Option<String>,
+	/// The upstream address this request went to, held until the request is done
+	lease: Option<Lease>,
+	/// What the upstream's circuit breaker let this request through with
+	permit: Option<Permit>,
 	proxy_start: Option<time::Instant>,
 }
 
@@ -61,9 +168,13 @@ impl ProxyHttp for Umami {
 	type CTX = Ctx;
 	fn new_ctx(&self) -> Self::CTX {
 		Ctx {
+			settings: self.settings.load_full(),
 			request_body_buffer: Vec::new(),
 			location: None,
 			ingress: String::new(),
+			upstream: None,
+			lease: None,
+			permit: None,
 			proxy_start: None,
 		}
 	}
@@ -93,11 +204,17 @@ impl ProxyHttp for Umami {
 			// https://en.wikipedia.org/wiki/Double-checked_locking
 			{
 				// This should have a gauge to show that we only ever have one (or zero ) of these
-				tokio::spawn(async {
-					let e1 = k8s::populate_cache();
-					warn!("populating cache: {:?}", e1.await);
-					let _e2 = k8s::run_watcher().await;
-				});
+				if ctx.settings.conf.k8s.enabled {
+					let label_selector = ctx.settings.conf.k8s.label_selector.clone();
+					tokio::spawn(async move {
+						let e1 = k8s::populate_cache();
+						warn!("populating cache: {:?}", e1.await);
+						let _e2 = k8s::run_watcher(&label_selector).await;
+					});
+					tokio::spawn(async {
+						let _e3 = k8s::run_configmap_watcher().await;
+					});
+				}
 			}
 		}
 
@@ -143,43 +260,69 @@ impl ProxyHttp for Umami {
 		ctx.location = Some(Location { city, country });
 
 		let user_agent = session.downstream_session.get_header("USER-AGENT").cloned();
-		match user_agent {
-			Some(ua) => match ua.to_str() {
+		if let Some(ua) = user_agent {
+			match ua.to_str() {
 				Ok(ua) => {
-					let bot = self.bots.is_bot(ua);
+					let bot = ctx.settings.conf.bots.block && ctx.settings.bots.is_bot(ua);
 
 					if bot {
 						session.respond_error(403).await?;
 						return Ok(bot);
 					}
-					Ok(false)
 				},
 				Err(e) => {
 					error!("Err: {e}");
-					return Ok(false);
 				},
-			},
-			None => Ok(false),
+			}
+		}
+
+		if route_request(session, ctx).await? {
+			return Ok(true);
+		}
+
+		// Fail fast instead of queueing events behind an upstream that's down
+		let breaker = ctx.settings.balancer(ctx.upstream.as_deref()).breaker();
+		ctx.permit = breaker.allow();
+		if ctx.permit.is_none() {
+			let error: &str = UmamiProxyError::CircuitOpen.into();
+			PROXY_ERRORS.with_label_values(&[error]).inc();
+			warn!(
+				"Circuit open for upstream {:?}: {}",
+				ctx.upstream.as_deref().unwrap_or("default"),
+				session.request_summary()
+			);
+			let body = json!({ "error": UmamiProxyError::CircuitOpen.to_string() }).to_string();
+			let mut response_header = ResponseHeader::build(503, None)?;
+			response_header.insert_header("Retry-After", breaker.retry_after())?;
+			response_header.insert_header("Content-Type", "application/json")?;
+			response_header.insert_header("Content-Length", body.len())?;
+			session
+				.write_response_header(Box::new(response_header), false)
+				.await?;
+			session
+				.write_response_body(Some(Bytes::from(body)), true)
+				.await?;
+			return Ok(true);
 		}
+		Ok(false)
 	}
 	// This guy should be the upstream host, all requests through the proxy gets sent th upstream_peer
 	async fn upstream_peer(
 		&self,
 		session: &mut Session,
-		_ctx: &mut Self::CTX,
+		ctx: &mut Self::CTX,
 	) -> Result<Box<HttpPeer>> {
 		let uri = session.downstream_session.req_header().as_owned_parts().uri;
 		UPSTREAM_PEER.with_label_values(&[uri.path()]).inc();
 
-		let peer = Box::new(HttpPeer::new(
-			format!("{}:{}", self.conf.host, self.conf.port)
-				.to_socket_addrs()
-				.expect("Umami specified `host` & `port` should give valid `std::net::SocketAddr`")
-				.next()
-				.expect("SocketAddr should resolve to at least 1 IP address"),
-			self.conf.sni.is_some(),
-			self.conf.sni.clone().unwrap_or_default(),
-		));
+		let lease = ctx
+			.settings
+			.balancer(ctx.upstream.as_deref())
+			.lease()
+			.await?;
+		let peer = Box::new(lease.peer());
+		// Replacing an earlier lease (pingora retries) releases it
+		ctx.lease = Some(lease);
 		Ok(peer)
 	}
 
@@ -202,41 +345,17 @@ impl ProxyHttp for Umami {
 		if end_of_stream {
 			// This is the last chunk, we can process the data now
 			if !ctx.request_body_buffer.is_empty() {
-				let content_type = session
-					.downstream_session
-					.get_header("content-type")
-					.map_or_else(
-						|| String::from("no content type header"),
-						|x| {
-							x.to_str()
-								.map_or(String::new(), std::borrow::ToOwned::to_owned)
-						},
-					);
-
-				// We should do content negotiation, apparently
-				// This must be a downsteam misconfiguration, surely??
-				let json: Value = if content_type
-					.to_lowercase()
-					.contains("application/x-www-form-urlencoded")
-				{
-					parse_url_encoded(&String::from_utf8_lossy(&ctx.request_body_buffer))?
-				} else {
-					serde_json::from_slice(&ctx.request_body_buffer)
-						.or_err(
-							pingora::ErrorType::Custom(
-								UmamiProxyError::RequestContainsInvalidJson.into(),
-							),
-							"Failed to parse request body",
-						)
-						.map_err(|e| *e)?
-				};
+				let settings = Arc::clone(&ctx.settings);
+				let json = parse_body(session, &ctx.request_body_buffer)?;
 
 				// Validate and filter fields that are too long
-				let (mut json, violations) = validate::validate_and_filter(&json);
+				let max_field_length = settings.conf.limits.max_field_length;
+				let (mut json, violations) = validate::validate_and_filter(&json, max_field_length);
 
 				// If there were violations, send error response to client but continue processing
 				if !violations.is_empty() {
-					let error_response = validate::create_error_response(&violations);
+					let error_response =
+						validate::create_error_response(&violations, max_field_length);
 					let error_body = serde_json::to_string(&error_response)
 						.unwrap_or_else(|_| String::from(r#"{"error":"Field validation failed"}"#));
 
@@ -252,22 +371,32 @@ impl ProxyHttp for Umami {
 						.await?;
 
 					// Log the violations for monitoring
-					let error_msg = validate::format_error_message(&violations);
+					let error_msg = validate::format_error_message(&violations, max_field_length);
 					warn!(
 						"Field validation failed, truncated offending fields: {}",
 						error_msg
 					);
 				}
 
-				redact::traverse_and_redact(&mut json);
+				let app = cache::get_app_info_with_longest_prefix(
+					&get_website_url(&json).unwrap_or_default(),
+				);
+				let policy = app.as_ref().and_then(|app| app.redaction.as_deref());
+				let original = settings.shadow.as_ref().map(|_| json.clone());
+				settings
+					.redactor
+					.for_event(get_website_id(&json), policy)
+					.traverse_and_redact(&mut json);
+				if let (Some(shadow), Some(original)) = (&settings.shadow, original) {
+					let app_name = app.as_ref().map_or("unknown", |app| app.app_name.as_str());
+					shadow.compare(original, &json, &settings.redactor, policy, app_name);
+				}
 				annotate::with_proxy_version(
 					&mut json,
 					&format!("{}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
 				);
 
-				if let Some(app) = cache::get_app_info_with_longest_prefix(
-					&get_website_url(&json).unwrap_or_default(),
-				) {
+				if let Some(app) = app {
 					annotate::with_app_info(&mut json, &app, &ctx.ingress);
 				}
 
@@ -308,14 +437,21 @@ impl ProxyHttp for Umami {
 		Ok(())
 	}
 
-	/// Redact path and query parameters of request
-	/// TODO: Also ensure that path fragments are redacted?
+	/// Redact path and query parameters of request. Request URIs have no fragment, `http::Uri`
+	/// drops it
 	async fn upstream_request_filter(
 		&self,
 		_session: &mut Session,
 		upstream_request: &mut RequestHeader,
 		ctx: &mut Self::CTX,
 	) -> Result<()> {
+		// Before any of our own headers go in, so the policy can't take them out
+		headers::apply(
+			&ctx.settings.conf.headers,
+			&ctx.settings.redactor,
+			upstream_request,
+		)?;
+
 		// It's hard to know how big the body is before we start touching it
 		// We work around that by removing content length and setting the
 		// transfer encoding as chunked. The source code in pingora core looks like it would
@@ -324,27 +460,28 @@ impl ProxyHttp for Umami {
 		upstream_request
 			.insert_header("Transfer-Encoding", "Chunked")
 			.expect("Needs correct transfer-encoding scheme header set");
+		let upstream = ctx.settings.conf.upstream(ctx.upstream.as_deref());
 		upstream_request
-			.insert_header("Host", &self.conf.host)
+			.insert_header("Host", &upstream.host)
 			.expect("Needs correct Host header");
 
-		// Prepend path if UMAMI_PATH is configured (useful for testing with request baskets)
-		if let Some(base_path) = &self.conf.path {
-			let current_uri = &upstream_request.uri;
-			let new_path = format!("{}{}", base_path, current_uri.path());
-
-			// Preserve query string if present
-			let new_uri = if let Some(query) = current_uri.query() {
-				format!("{}?{}", new_path, query)
-			} else {
-				new_path
-			};
+		// The body isn't read yet, so there's no website id or app policy to go by
+		let current_uri = &upstream_request.uri;
+		let path_and_query = current_uri
+			.path_and_query()
+			.map_or_else(|| current_uri.path(), |p| p.as_str());
+		let redacted = ctx.settings.redactor.redact_bare_url(path_and_query);
 
+		// Prepend path if UMAMI_PATH is configured (useful for testing with request baskets)
+		let new_uri = match &upstream.path {
+			Some(base_path) => format!("{base_path}{redacted}"),
+			None => redacted,
+		};
+		if new_uri != path_and_query {
 			upstream_request.set_uri(
 				new_uri
-					.as_bytes()
-					.try_into()
-					.expect("Failed to construct new URI with path prefix"),
+					.parse()
+					.or_err(ErrType::InternalError, "Redacted request URI doesn't parse")?,
 			);
 		}
 
@@ -372,6 +509,15 @@ impl ProxyHttp for Umami {
 		// TODO: Wrap this into a prometheus metric
 		let _proxy_duration = ctx.proxy_start.map(|start_time| start_time.elapsed());
 
+		if let (Some(lease), Some(permit)) = (&ctx.lease, ctx.permit) {
+			match e {
+				None => lease.breaker().record_success(permit),
+				Some(err) if is_upstream_failure(err) => lease.breaker().record_failure(permit),
+				// Invalid bodies and the like say nothing about the upstream
+				Some(_) => {},
+			}
+		}
+
 		let Some(err) = e else {
 			// happy path
 			HANDLED_REQUESTS.inc();
@@ -425,6 +571,100 @@ impl ProxyHttp for Umami {
 	}
 }
 
+/// Errors that count towards opening the circuit breaker
+fn is_upstream_failure(err: &Error) -> bool {
+	match err.etype {
+		ErrType::ConnectTimedout
+		| ErrType::ConnectRefused
+		| ErrType::ConnectNoRoute
+		| ErrType::ConnectError
+		| ErrType::TLSHandshakeFailure
+		| ErrType::TLSHandshakeTimedout => true,
+		ErrType::ReadTimedout
+		| ErrType::WriteTimedout
+		| ErrType::ReadError
+		| ErrType::WriteError
+		| ErrType::ConnectionClosed => err.esource == ErrorSource::Upstream,
+		_ => false,
+	}
+}
+
+/// Picks the upstream for this request and stores it in `ctx`. Routes on website id or namespace
+/// need the body, so only then is it read here, ahead of `request_body_filter`
+async fn route_request(session: &mut Session, ctx: &mut Ctx) -> Result<bool> {
+	let settings = Arc::clone(&ctx.settings);
+	let routes = &settings.conf.routes;
+	if routes.is_empty() {
+		return Ok(false);
+	}
+
+	let path = session.req_header().uri.path().to_owned();
+	let mut key = route::RouteKey {
+		origin: &ctx.ingress,
+		path: &path,
+		..route::RouteKey::default()
+	};
+
+	let json = if routes.iter().any(Route::needs_body) {
+		let mut body = Vec::new();
+		while let Some(chunk) = session.read_request_body().await? {
+			body.extend_from_slice(&chunk);
+			if body.len() > PREFETCH_LIMIT {
+				warn!("Body too large to route on: {}", session.request_summary());
+				session.respond_error(413).await?;
+				return Ok(true);
+			}
+		}
+		// A body that doesn't parse is rejected in `request_body_filter`, route on the rest
+		parse_body(session, &body).ok()
+	} else {
+		None
+	};
+	let namespace = json
+		.as_ref()
+		.and_then(get_website_url)
+		.and_then(|url| cache::get_app_info_with_longest_prefix(&url))
+		.map(|app| app.namespace);
+	key.website_id = json.as_ref().and_then(get_website_id);
+	key.namespace = namespace.as_deref();
+
+	let upstream = route::select(routes, &key).map(ToOwned::to_owned);
+	ROUTED_REQUESTS
+		.with_label_values(&[upstream.as_deref().unwrap_or("default")])
+		.inc();
+	ctx.upstream = upstream;
+	Ok(false)
+}
+
+fn parse_body(session: &Session, body: &[u8]) -> Result<Value, pingora::Error> {
+	let content_type = session
+		.downstream_session
+		.get_header("content-type")
+		.map_or_else(
+			|| String::from("no content type header"),
+			|x| {
+				x.to_str()
+					.map_or(String::new(), std::borrow::ToOwned::to_owned)
+			},
+		);
+
+	// We should do content negotiation, apparently
+	// This must be a downsteam misconfiguration, surely??
+	if content_type
+		.to_lowercase()
+		.contains("application/x-www-form-urlencoded")
+	{
+		parse_url_encoded(&String::from_utf8_lossy(body))
+	} else {
+		serde_json::from_slice(body)
+			.or_err(
+				pingora::ErrorType::Custom(UmamiProxyError::RequestContainsInvalidJson.into()),
+				"Failed to parse request body",
+			)
+			.map_err(|e| *e)
+	}
+}
+
 fn parse_url_encoded(data: &str) -> Result<Value, pingora::Error> {
 	let parsed: HashMap<String, String> = serde_urlencoded::from_str(data)
 		.explain_err(
@@ -450,6 +690,13 @@ fn get_website_url(value: &Value) -> Option<String> {
 		.map(String::from)
 }
 
+fn get_website_id(value: &Value) -> Option<&str> {
+	value
+		.get("payload")
+		.and_then(|p| p.get("website"))
+		.and_then(|v| v.as_str())
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -520,4 +767,71 @@ mod tests {
 		let parsed = parse_url_encoded(input).expect("Failed to parse");
 		assert_eq!(parsed, expected);
 	}
+
+	#[test]
+	fn test_settings_reject_unknown_disabled_label() {
+		let mut conf = Config::default();
+		conf.redaction
+			.disabled_labels
+			.insert("PROXY-ADDRESS".into());
+		assert!(Settings::new(conf.clone()).is_ok());
+
+		conf.redaction.disabled_labels.insert("PROXY-ADRESS".into());
+		let Err(ConfigError::Invalid(violations)) = Settings::new(conf) else {
+			panic!("An unknown label should be rejected");
+		};
+		assert_eq!(violations.len(), 1);
+		assert_eq!(violations[0].field, "redaction.disabled_labels");
+	}
+
+	#[test]
+	fn test_settings_reject_missing_rules_file() {
+		let mut conf = Config::default();
+		conf.redaction.rules_file = Some("/nonexistent/privacy-rules.yaml".into());
+		let Err(ConfigError::Invalid(violations)) = Settings::new(conf) else {
+			panic!("A rules file that can't be read should be rejected");
+		};
+		assert_eq!(violations.len(), 1);
+		assert_eq!(violations[0].field, "redaction.rules_file");
+	}
+
+	#[test]
+	fn test_reload_keeps_unchanged_balancers() {
+		let mut conf = Config::default();
+		conf.upstreams.insert("beta".into(), conf.upstream.clone());
+		let previous = Settings::new(conf.clone()).unwrap();
+
+		conf.upstreams.get_mut("beta").unwrap().port = 3001;
+		let settings = Settings::reload(conf.clone(), &previous).unwrap();
+		assert!(Arc::ptr_eq(
+			&settings.default_balancer,
+			&previous.default_balancer
+		));
+		assert!(
+			!Arc::ptr_eq(&settings.balancers["beta"], &previous.balancers["beta"]),
+			"A changed upstream starts over"
+		);
+
+		conf.circuit_breaker.open_secs += 1;
+		let reloaded = Settings::reload(conf, &settings).unwrap();
+		assert!(
+			!Arc::ptr_eq(&reloaded.default_balancer, &settings.default_balancer),
+			"So does every upstream when the breaker settings change"
+		);
+	}
+
+	#[test]
+	fn test_settings_reject_unreadable_upstream_tls() {
+		let mut conf = Config::default();
+		let mut beta = conf.upstream.clone();
+		beta.sni = Some("umami-beta.local".into());
+		beta.tls.ca = Some("/nonexistent/ca.pem".into());
+		conf.upstreams.insert("beta".into(), beta);
+
+		let Err(ConfigError::Invalid(violations)) = Settings::new(conf) else {
+			panic!("A CA bundle that can't be read should be rejected");
+		};
+		assert_eq!(violations.len(), 1);
+		assert_eq!(violations[0].field, "upstreams.beta.tls");
+	}
 }
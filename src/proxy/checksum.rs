@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+const HETU_CHECK_CHARS: &[u8; 31] = b"0123456789ABCDEFHJKLMNPRSTUVWXY";
+
+/// Checks a privacy pattern's matches have to pass before they're redacted, so number formats
+/// that are common outside PII (order numbers, timestamps) aren't taken
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Checksum {
+	/// Payment card numbers: 13-19 digits, an issuer digit and Luhn
+	Card,
+	/// mod 97 over the rearranged IBAN
+	Iban,
+	/// Swedish personnummer and samordningsnummer: date and Luhn
+	SePersonnummer,
+	/// Danish CPR: date and the mod 11 weights
+	DkCpr,
+	/// Finnish HETU: date, century sign and the mod 31 check character
+	FiHetu,
+	/// Norwegian bank account numbers: 11 digits and the mod 11 weights
+	NoAccount,
+}
+
+impl Checksum {
+	/// Separators the patterns allow (spaces, hyphens) are ignored
+	pub fn check(self, s: &str) -> bool {
+		match self {
+			Self::Card => card(s),
+			Self::Iban => iban(s),
+			Self::SePersonnummer => se_personnummer(s),
+			Self::DkCpr => dk_cpr(s),
+			Self::FiHetu => fi_hetu(s),
+			Self::NoAccount => no_account(s),
+		}
+	}
+}
+
+/// Whether the date exists, `year` being the full year
+pub const fn is_date(year: u32, month: u32, day: u32) -> bool {
+	let leap = year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400));
+	let days_in_month = match month {
+		1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+		4 | 6 | 9 | 11 => 30,
+		2 if leap => 29,
+		2 => 28,
+		_ => return false,
+	};
+	day >= 1 && day <= days_in_month
+}
+
+/// Shannon entropy in bits per character. Random tokens score high, words and numbers lower
+pub fn entropy(s: &str) -> f64 {
+	let mut counts = HashMap::new();
+	for c in s.chars() {
+		*counts.entry(c).or_insert(0_usize) += 1;
+	}
+	let len = s.chars().count() as f64;
+	counts
+		.values()
+		.map(|&count| {
+			let p = count as f64 / len;
+			-p * p.log2()
+		})
+		.sum()
+}
+
+fn digits(s: &str) -> Vec<u32> {
+	s.chars().filter_map(|c| c.to_digit(10)).collect()
+}
+
+/// Two digits starting at `i`
+fn pair(d: &[u32], i: usize) -> u32 {
+	d[i] * 10 + d[i + 1]
+}
+
+fn luhn(d: &[u32]) -> bool {
+	let sum: u32 = d
+		.iter()
+		.rev()
+		.enumerate()
+		.map(|(i, &d)| match (i % 2, d * 2) {
+			(0, _) => d,
+			(_, doubled) if doubled > 9 => doubled - 9,
+			(_, doubled) => doubled,
+		})
+		.sum();
+	sum.is_multiple_of(10)
+}
+
+fn card(s: &str) -> bool {
+	let d = digits(s);
+	(13..=19).contains(&d.len()) && (2..=6).contains(&d[0]) && luhn(&d)
+}
+
+fn iban(s: &str) -> bool {
+	let iban: Vec<u8> = s.bytes().filter(|b| *b != b' ').collect();
+	if !(15..=34).contains(&iban.len())
+		|| !iban[..2].iter().all(u8::is_ascii_uppercase)
+		|| !iban[2..4].iter().all(u8::is_ascii_digit)
+	{
+		return false;
+	}
+	let (country, account) = iban.split_at(4);
+	let mut remainder = 0;
+	for &b in account.iter().chain(country) {
+		remainder = match b {
+			b'0'..=b'9' => (remainder * 10 + u32::from(b - b'0')) % 97,
+			b'A'..=b'Z' => (remainder * 100 + u32::from(b - b'A') + 10) % 97,
+			_ => return false,
+		};
+	}
+	remainder == 1
+}
+
+fn se_personnummer(s: &str) -> bool {
+	let d = digits(s);
+	let (year, d) = match d.len() {
+		12 => (pair(&d, 0) * 100 + pair(&d, 2), &d[2..]),
+		// The century only matters for 29 February, and 2000 is a leap year
+		10 => (2000 + pair(&d, 0), &d[..]),
+		_ => return false,
+	};
+	let day = pair(d, 4);
+	// Samordningsnummer add 60 to the day
+	let day = if day > 60 { day - 60 } else { day };
+	is_date(year, pair(d, 2), day) && luhn(d)
+}
+
+fn dk_cpr(s: &str) -> bool {
+	const WEIGHTS: [u32; 10] = [4, 3, 2, 7, 6, 5, 4, 3, 2, 1];
+	let d = digits(s);
+	if d.len() != 10 {
+		return false;
+	}
+	let year = pair(&d, 4);
+	// From the first digit of the serial number
+	let century = match (d[6], year) {
+		(0..=3, _) | (4 | 9, 37..) => 1900,
+		(4 | 9, _) | (5..=8, ..=57) => 2000,
+		_ => 1800,
+	};
+	let sum: u32 = d.iter().zip(WEIGHTS).map(|(d, w)| d * w).sum();
+	is_date(century + year, pair(&d, 2), pair(&d, 0)) && sum.is_multiple_of(11)
+}
+
+fn fi_hetu(s: &str) -> bool {
+	let b = s.as_bytes();
+	if b.len() != 11 || !b[..6].iter().chain(&b[7..10]).all(u8::is_ascii_digit) {
+		return false;
+	}
+	let century = match b[6] {
+		b'+' => 1800,
+		b'-' | b'U'..=b'Y' => 1900,
+		b'A'..=b'F' => 2000,
+		_ => return false,
+	};
+	let d = digits(&s[..10]);
+	let number = d.iter().fold(0, |n, d| n * 10 + d);
+	is_date(century + pair(&d, 4), pair(&d, 2), pair(&d, 0))
+		&& HETU_CHECK_CHARS[(number % 31) as usize] == b[10]
+}
+
+fn no_account(s: &str) -> bool {
+	// A check digit that would have to be 10 isn't used, so those numbers never add up
+	const WEIGHTS: [u32; 11] = [5, 4, 3, 2, 7, 6, 5, 4, 3, 2, 1];
+	let d = digits(s);
+	let sum: u32 = d.iter().zip(WEIGHTS).map(|(d, w)| d * w).sum();
+	d.len() == 11 && sum.is_multiple_of(11)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_checksums() {
+		for (checksum, valid, invalid) in [
+			(
+				Checksum::Card,
+				&[
+					"4111111111111111",
+					"4111 1111 1111 1111",
+					"3782 822463 10005",
+				][..],
+				&["4111111111111112", "1700000000000", "0000000000000"][..],
+			),
+			(
+				Checksum::Iban,
+				&[
+					"NO9386011117947",
+					"DE89 3704 0044 0532 0130 00",
+					"GB29NWBK60161331926819",
+				],
+				&["NO9386011117948", "NO93 8601 1117 94", "no9386011117947"],
+			),
+			(
+				Checksum::SePersonnummer,
+				&["811218-9876", "19811218-9876", "811278-9873"],
+				&["811218-9877", "811318-9876", "811218-98"],
+			),
+			(
+				Checksum::DkCpr,
+				&["070761-4285", "0707614285"],
+				&["070761-4286", "320761-4283"],
+			),
+			(
+				Checksum::FiHetu,
+				&["131052-308T", "131052A308T"],
+				&["131052-308U", "131052G308T", "311352-308T"],
+			),
+			(
+				Checksum::NoAccount,
+				&["1234.56.78903", "86011117947", "8601 11 17947"],
+				&["1234.56.78901", "86011117948", "8601111794"],
+			),
+		] {
+			for s in valid {
+				assert!(checksum.check(s), "{checksum:?} {s}");
+			}
+			for s in invalid {
+				assert!(!checksum.check(s), "{checksum:?} {s}");
+			}
+		}
+	}
+
+	#[test]
+	fn test_entropy() {
+		assert_eq!(entropy(""), 0.0);
+		assert_eq!(entropy("aaaa"), 0.0);
+		assert_eq!(entropy("abab"), 1.0);
+		assert!(entropy("4f9Kq2ZxR7mT1pWb") > 3.5);
+		assert!(entropy("1234567890123456") < 3.5);
+	}
+}
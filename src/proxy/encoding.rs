@@ -0,0 +1,206 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use base64::alphabet::{Alphabet, STANDARD, URL_SAFE};
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use once_cell::sync::Lazy;
+use percent_encoding::percent_decode_str;
+use regex::Regex;
+
+/// Characters that go unencoded in URLs, except the ones that separate parts and parameters
+/// (`/?#&=`), so a run is at most one path segment or parameter value
+static PERCENT_RUN_REGEX: Lazy<Regex> = Lazy::new(|| {
+	Regex::new(r"[A-Za-z0-9\-._~!$'()*+,;:@%]+")
+		.expect("Hard-coded regex expression should be valid")
+});
+static PERCENT_ESCAPE_REGEX: Lazy<Regex> = Lazy::new(|| {
+	Regex::new(r"%[0-9A-Fa-f]{2}").expect("Hard-coded regex expression should be valid")
+});
+/// Long enough that words and short ids rarely decode to text
+static BASE64_RUN_REGEX: Lazy<Regex> = Lazy::new(|| {
+	Regex::new(r"[A-Za-z0-9+/_\-]{16,}={0,2}").expect("Hard-coded regex expression should be valid")
+});
+
+/// Finds percent-encoded (`ola%40nav.no`), `+`-encoded (`Ola+Nordmann`) and base64 or base64url
+/// runs in `s` and runs `redact` on what they decode to. Runs that come back changed are encoded
+/// the way they were and put back, the rest are left as they are. `None` if nothing changed
+pub fn redact_encoded(s: &str, redact: impl Fn(&str) -> String) -> Option<String> {
+	let percent_run = |run: &str| {
+		let plus = run.contains('+');
+		if !plus && !PERCENT_ESCAPE_REGEX.is_match(run) {
+			return None;
+		}
+		let decoded = percent_decode(run, plus)?;
+		let redacted = redact(&decoded);
+		(redacted != decoded).then(|| percent_encode(&redacted, run, plus))
+	};
+	let after_percent = if s.contains(['%', '+']) {
+		replace_runs(s, &PERCENT_RUN_REGEX, percent_run)
+	} else {
+		None
+	};
+	let after_base64 = replace_runs(
+		after_percent.as_deref().unwrap_or(s),
+		&BASE64_RUN_REGEX,
+		|run| {
+			let (engine, padded) = base64_engine(run)?;
+			let decoded = String::from_utf8(engine.decode(run).ok()?).ok()?;
+			if decoded
+				.chars()
+				.any(|c| c.is_control() && !c.is_whitespace())
+			{
+				return None;
+			}
+			let redacted = redact(&decoded);
+			(redacted != decoded).then(|| {
+				let encoded = engine.encode(redacted);
+				if padded {
+					encoded
+				} else {
+					encoded.trim_end_matches('=').to_owned()
+				}
+			})
+		},
+	);
+	after_base64.or(after_percent)
+}
+
+/// `s` with the runs `f` has a replacement for replaced, `None` if it had none
+fn replace_runs(s: &str, regex: &Regex, f: impl Fn(&str) -> Option<String>) -> Option<String> {
+	let mut result = String::new();
+	let mut last = 0;
+	for run in regex.find_iter(s) {
+		if let Some(replacement) = f(run.as_str()) {
+			result.push_str(&s[last..run.start()]);
+			result.push_str(&replacement);
+			last = run.end();
+		}
+	}
+	if last == 0 {
+		return None;
+	}
+	result.push_str(&s[last..]);
+	Some(result)
+}
+
+fn percent_decode(run: &str, plus: bool) -> Option<String> {
+	let spaced = if plus {
+		run.replace('+', " ")
+	} else {
+		run.to_owned()
+	};
+	let decoded = percent_decode_str(&spaced).decode_utf8().ok()?;
+	(decoded != run).then(|| decoded.into_owned())
+}
+
+/// Encodes `decoded` like `original` was: what it had percent-encoded is encoded again, and so is
+/// anything that can't go unencoded. Label brackets are left as they are, like everywhere else in
+/// a redacted URL
+fn percent_encode(decoded: &str, original: &str, plus: bool) -> String {
+	let escaped: HashSet<char> = PERCENT_ESCAPE_REGEX
+		.find_iter(original)
+		.filter_map(|escape| percent_decode_str(escape.as_str()).decode_utf8().ok())
+		.filter_map(|c| c.chars().next())
+		.collect();
+	let mut encoded = String::with_capacity(decoded.len());
+	for c in decoded.chars() {
+		if c == ' ' && plus {
+			encoded.push('+');
+		} else if (c.is_ascii_alphanumeric()
+			|| "-._~!$'()*,;:@[]".contains(c)
+			|| (c == '+' && !plus))
+			&& !escaped.contains(&c)
+		{
+			encoded.push(c);
+		} else {
+			let mut bytes = [0; 4];
+			for b in c.encode_utf8(&mut bytes).bytes() {
+				write!(encoded, "%{b:02X}").expect("Writing to a String can't fail");
+			}
+		}
+	}
+	encoded
+}
+
+/// The engine for the run's alphabet and whether it's padded. `None` if it can't be base64
+fn base64_engine(run: &str) -> Option<(GeneralPurpose, bool)> {
+	let standard = run.contains(['+', '/']);
+	let url_safe = run.contains(['-', '_']);
+	let alphabet: &Alphabet = match (standard, url_safe) {
+		(true, true) => return None,
+		(_, false) => &STANDARD,
+		(false, true) => &URL_SAFE,
+	};
+	let unpadded = run.trim_end_matches('=');
+	if unpadded.len() % 4 == 1 {
+		return None;
+	}
+	let config = GeneralPurposeConfig::new()
+		.with_decode_padding_mode(DecodePaddingMode::Indifferent)
+		.with_encode_padding(true);
+	Some((
+		GeneralPurpose::new(alphabet, config),
+		unpadded.len() != run.len(),
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	fn redact(s: &str) -> Option<String> {
+		redact_encoded(s, |decoded| {
+			decoded
+				.replace("ola@nav.no", "[PROXY-EMAIL]")
+				.replace("/home/ola", "[PROXY-FILEPATH]")
+				.replace("Ola Nordmann", "[PROXY-NAME]")
+		})
+	}
+
+	#[test]
+	fn test_percent_and_plus() {
+		assert_eq!(
+			redact("epost=ola%40nav.no").as_deref(),
+			Some("epost=[PROXY-EMAIL]")
+		);
+		assert_eq!(
+			redact("fil %2Fhome%2Fola og s%C3%B8k").as_deref(),
+			Some("fil [PROXY-FILEPATH] og s%C3%B8k")
+		);
+		assert_eq!(
+			redact("navn=Ola+Nordmann+og+ola%40nav.no%2C%20hei").as_deref(),
+			Some("navn=[PROXY-NAME]+og+[PROXY-EMAIL]%2C+hei")
+		);
+		assert_eq!(redact("s%C3%B8k+etter+noe"), None);
+		assert_eq!(redact("100%-sikkert 2+2"), None);
+	}
+
+	#[test]
+	fn test_base64() {
+		let state = base64::engine::general_purpose::URL_SAFE_NO_PAD
+			.encode(r#"{"epost":"ola@nav.no","side":"/minside"}"#);
+		let redacted = redact(&format!("state={state}&x=1")).unwrap();
+		let encoded = redacted
+			.strip_prefix("state=")
+			.and_then(|s| s.strip_suffix("&x=1"))
+			.unwrap();
+		assert!(!encoded.contains('='));
+		assert_eq!(
+			base64::engine::general_purpose::URL_SAFE_NO_PAD
+				.decode(encoded)
+				.unwrap(),
+			br#"{"epost":"[PROXY-EMAIL]","side":"/minside"}"#
+		);
+
+		let padded = base64::engine::general_purpose::STANDARD.encode("Hei, jeg er Ola Nordmann!");
+		assert_eq!(
+			redact(&padded),
+			Some(base64::engine::general_purpose::STANDARD.encode("Hei, jeg er [PROXY-NAME]!"))
+		);
+		// Decodes to bytes, not text
+		assert_eq!(redact("d41d8cd98f00b204e9800998ecf8427e"), None);
+	}
+}
@@ -0,0 +1,336 @@
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use super::pseudonym::Pseudonymizer;
+use crate::config::{FieldAction, FieldRule, Violation};
+
+#[derive(Debug, PartialEq, Eq)]
+enum Segment {
+	Key(String),
+	/// `*`, any key or array index
+	Any,
+	/// `[*]`
+	AnyIndex,
+	Index(usize),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Step<'a> {
+	Key(&'a str),
+	Index(usize),
+}
+
+/// Where a value sits in the event. Each level of the traversal keeps its own step on the stack
+#[derive(Clone, Copy, Debug)]
+pub struct Path<'a> {
+	parent: Option<&'a Path<'a>>,
+	step: Step<'a>,
+}
+
+impl<'a> Path<'a> {
+	/// `parent` is `None` for fields of the top-level value
+	pub const fn child(parent: Option<&'a Path<'a>>, step: Step<'a>) -> Self {
+		Self { parent, step }
+	}
+}
+
+#[derive(Debug)]
+pub enum Action {
+	Drop,
+	Keep,
+	Label(String),
+	/// Keyed with the pseudonymization secret
+	Hash(Arc<Pseudonymizer>),
+}
+
+#[derive(Debug)]
+struct Rule {
+	segments: Vec<Segment>,
+	action: Action,
+}
+
+impl Rule {
+	fn matches(&self, path: &Path) -> bool {
+		let mut node = Some(path);
+		// Compared from the field upwards, so a mismatch near the leaf is found first
+		for segment in self.segments.iter().rev() {
+			let Some(path) = node else {
+				return false;
+			};
+			let matches = match (segment, path.step) {
+				(Segment::Key(expected), Step::Key(key)) => expected == key,
+				(Segment::Index(expected), Step::Index(index)) => *expected == index,
+				(Segment::Any, _) | (Segment::AnyIndex, Step::Index(_)) => true,
+				_ => false,
+			};
+			if !matches {
+				return false;
+			}
+			node = path.parent;
+		}
+		node.is_none()
+	}
+}
+
+/// `redaction.field_rules`, compiled
+#[derive(Debug, Default)]
+pub struct FieldRules {
+	rules: Vec<Rule>,
+}
+
+impl FieldRules {
+	/// `pseudonymizer` keys `action: hash`, which isn't allowed without one
+	pub fn new(
+		rules: &[FieldRule],
+		pseudonymizer: Option<&Arc<Pseudonymizer>>,
+	) -> Result<Self, Vec<Violation>> {
+		let mut violations = Vec::new();
+		let mut compiled = Vec::new();
+		for (i, rule) in rules.iter().enumerate() {
+			let field = format!("redaction.field_rules[{i}]");
+			let action =
+				match compile_action(&field, rule.action, rule.label.as_ref(), pseudonymizer) {
+					Ok(action) => action,
+					Err(violation) => {
+						violations.push(violation);
+						continue;
+					},
+				};
+			match parse(&rule.path) {
+				Ok(segments) => compiled.push(Rule { segments, action }),
+				Err(e) => violations.push(Violation::new(format!("{field}.path"), e)),
+			}
+		}
+		if violations.is_empty() {
+			Ok(Self { rules: compiled })
+		} else {
+			Err(violations)
+		}
+	}
+
+	/// What the first rule matching `path` does
+	pub fn action(&self, path: &Path) -> Option<&Action> {
+		self.rules
+			.iter()
+			.find(|rule| rule.matches(path))
+			.map(|rule| &rule.action)
+	}
+}
+
+/// `label` is required for `FieldAction::Label`, and not allowed for the others. `FieldAction::Hash`
+/// needs the pseudonymization key, an unkeyed hash of an FNR or phone number is easily reversed by
+/// trying them all. `field` is the rule's place in the config
+pub fn compile_action(
+	field: &str,
+	action: FieldAction,
+	label: Option<&String>,
+	pseudonymizer: Option<&Arc<Pseudonymizer>>,
+) -> Result<Action, Violation> {
+	match (action, label) {
+		(FieldAction::Label, Some(label)) if !label.is_empty() => Ok(Action::Label(label.clone())),
+		(FieldAction::Label, _) => Err(Violation::new(
+			format!("{field}.label"),
+			"is required for action: label",
+		)),
+		(_, Some(_)) => Err(Violation::new(
+			format!("{field}.label"),
+			"is only used with action: label",
+		)),
+		(FieldAction::Drop, None) => Ok(Action::Drop),
+		(FieldAction::Keep, None) => Ok(Action::Keep),
+		(FieldAction::Hash, None) => pseudonymizer
+			.map(|pseudonymizer| Action::Hash(Arc::clone(pseudonymizer)))
+			.ok_or_else(|| {
+				Violation::new(
+					format!("{field}.action"),
+					"hash needs redaction.pseudonymize.key_file",
+				)
+			}),
+	}
+}
+
+/// Everything but `Drop`, which the parent does by removing the field
+pub fn apply(action: &Action, value: &mut Value) {
+	match action {
+		Action::Drop | Action::Keep => {},
+		Action::Label(label) => *value = Value::String(format!("[{label}]")),
+		Action::Hash(pseudonymizer) => {
+			let hash = match &*value {
+				Value::String(s) => pseudonymizer.hash(s),
+				other => pseudonymizer.hash(&other.to_string()),
+			};
+			*value = Value::String(hash);
+		},
+	}
+}
+
+/// What `apply` makes of a string
+pub fn apply_to_str(action: &Action, s: &str) -> String {
+	let mut value = Value::String(s.into());
+	apply(action, &mut value);
+	match value {
+		Value::String(s) => s,
+		_ => unreachable!("apply only ever makes strings"),
+	}
+}
+
+fn parse(path: &str) -> Result<Vec<Segment>, String> {
+	let mut segments = Vec::new();
+	for part in path.split('.') {
+		let (name, mut brackets) = part.split_at(part.find('[').unwrap_or(part.len()));
+		match name {
+			"" if brackets.is_empty() => return Err(format!("'{path}' has an empty segment")),
+			"" => {},
+			"*" => segments.push(Segment::Any),
+			name => segments.push(Segment::Key(name.into())),
+		}
+		while !brackets.is_empty() {
+			let index = brackets
+				.strip_prefix('[')
+				.and_then(|rest| rest.split_once(']'));
+			let Some((index, rest)) = index else {
+				return Err(format!("'{path}' has unbalanced brackets in '{part}'"));
+			};
+			segments.push(match index {
+				"*" => Segment::AnyIndex,
+				index => Segment::Index(
+					index
+						.parse()
+						.map_err(|_| format!("'{path}': '{index}' isn't an array index"))?,
+				),
+			});
+			brackets = rest;
+		}
+	}
+	Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Pseudonymize;
+	use pretty_assertions::assert_eq;
+
+	fn pseudonymizer() -> Arc<Pseudonymizer> {
+		Arc::new(Pseudonymizer::with_secret(
+			&Pseudonymize::default(),
+			b"0123456789abcdef",
+		))
+	}
+
+	fn rules(rules: &[(&str, FieldAction)]) -> FieldRules {
+		let rules: Vec<FieldRule> = rules
+			.iter()
+			.map(|(path, action)| FieldRule {
+				path: (*path).into(),
+				action: *action,
+				label: None,
+			})
+			.collect();
+		FieldRules::new(&rules, Some(&pseudonymizer())).unwrap()
+	}
+
+	#[test]
+	fn test_parse() {
+		assert_eq!(
+			parse("events[*].user_properties").unwrap(),
+			vec![
+				Segment::Key("events".into()),
+				Segment::AnyIndex,
+				Segment::Key("user_properties".into()),
+			]
+		);
+		assert_eq!(
+			parse("payload.*.list[2]").unwrap(),
+			vec![
+				Segment::Key("payload".into()),
+				Segment::Any,
+				Segment::Key("list".into()),
+				Segment::Index(2),
+			]
+		);
+		assert!(parse("payload..email").is_err());
+		assert!(parse("events[*").is_err());
+		assert!(parse("events[x]").is_err());
+		assert!(parse("events[0]x").is_err());
+	}
+
+	#[test]
+	fn test_first_matching_rule_wins() {
+		let rules = rules(&[
+			("payload.data.*.email", FieldAction::Keep),
+			("payload.data.*.*", FieldAction::Hash),
+			("events[*].user_properties", FieldAction::Drop),
+		]);
+
+		let payload = Path::child(None, Step::Key("payload"));
+		let data = Path::child(Some(&payload), Step::Key("data"));
+		let item = Path::child(Some(&data), Step::Index(0));
+		let email = Path::child(Some(&item), Step::Key("email"));
+		let phone = Path::child(Some(&item), Step::Key("phone"));
+		assert!(matches!(rules.action(&email), Some(Action::Keep)));
+		assert!(matches!(rules.action(&phone), Some(Action::Hash(_))));
+		assert!(
+			rules.action(&item).is_none(),
+			"Paths match whole, not by prefix"
+		);
+
+		let events = Path::child(None, Step::Key("events"));
+		let event = Path::child(Some(&events), Step::Index(3));
+		let properties = Path::child(Some(&event), Step::Key("user_properties"));
+		assert!(matches!(rules.action(&properties), Some(Action::Drop)));
+		let event = Path::child(Some(&events), Step::Key("3"));
+		let properties = Path::child(Some(&event), Step::Key("user_properties"));
+		assert!(
+			rules.action(&properties).is_none(),
+			"[*] is only array indices"
+		);
+	}
+
+	#[test]
+	fn test_hash_is_keyed() {
+		let pseudonymizer = pseudonymizer();
+		let action = Action::Hash(Arc::clone(&pseudonymizer));
+		let hash = apply_to_str(&action, "15019012317");
+		assert!(
+			hash.starts_with("[PROXY-HASH:") && hash.len() == 29,
+			"{hash}"
+		);
+		assert_eq!(apply_to_str(&action, "15019012317"), hash);
+		let mut number = Value::from(15_019_012_317_u64);
+		apply(&action, &mut number);
+		assert_eq!(number, Value::String(hash), "Numbers hash like their JSON");
+	}
+
+	#[test]
+	fn test_label_only_with_label_action() {
+		let rule = |action, label: Option<&str>| FieldRule {
+			path: "payload.email".into(),
+			action,
+			label: label.map(Into::into),
+		};
+		assert!(FieldRules::new(&[rule(FieldAction::Label, Some("PROXY-EMAIL"))], None).is_ok());
+		let violations = FieldRules::new(
+			&[
+				rule(FieldAction::Label, None),
+				rule(FieldAction::Drop, Some("PROXY-EMAIL")),
+				rule(FieldAction::Hash, None),
+			],
+			None,
+		)
+		.unwrap_err();
+		assert_eq!(
+			violations
+				.iter()
+				.map(|v| v.field.as_str())
+				.collect::<Vec<_>>(),
+			vec![
+				"redaction.field_rules[0].label",
+				"redaction.field_rules[1].label",
+				// Unkeyed, an FNR's hash is found by hashing every FNR
+				"redaction.field_rules[2].action",
+			]
+		);
+	}
+}
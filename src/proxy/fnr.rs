@@ -0,0 +1,88 @@
+use super::checksum::is_date;
+
+const FIRST_WEIGHTS: [u32; 9] = [3, 7, 6, 1, 8, 9, 4, 5, 2];
+const SECOND_WEIGHTS: [u32; 10] = [5, 4, 3, 2, 7, 6, 5, 4, 3, 2];
+
+/// Whether `s` is a fødselsnummer, D-number, H-number or synthetic test number: `DDMMYYIIIKK`,
+/// a plausible birth date, a three digit individual number and both mod 11 control digits right
+pub fn is_valid(s: &str) -> bool {
+	let Ok(digits) = <[u8; 11]>::try_from(s.as_bytes()) else {
+		return false;
+	};
+	if !digits.iter().all(u8::is_ascii_digit) {
+		return false;
+	}
+	let d = digits.map(|b| u32::from(b - b'0'));
+	has_plausible_date(&d)
+		&& control_digit(&d[..9], &FIRST_WEIGHTS) == Some(d[9])
+		&& control_digit(&d[..10], &SECOND_WEIGHTS) == Some(d[10])
+}
+
+fn control_digit(digits: &[u32], weights: &[u32]) -> Option<u32> {
+	let sum: u32 = digits.iter().zip(weights).map(|(d, w)| d * w).sum();
+	match 11 - sum % 11 {
+		11 => Some(0),
+		10 => None,
+		k => Some(k),
+	}
+}
+
+fn has_plausible_date(d: &[u32; 11]) -> bool {
+	let mut day = d[0] * 10 + d[1];
+	let mut month = d[2] * 10 + d[3];
+	let year = d[4] * 10 + d[5];
+	let individual = d[6] * 100 + d[7] * 10 + d[8];
+
+	// D-numbers add 40 to the day
+	if day > 40 {
+		day -= 40;
+	}
+	// Synthetic test numbers add 80 to the month, H-numbers 40
+	if month > 80 {
+		month -= 80;
+	} else if month > 40 {
+		month -= 40;
+	}
+
+	century(year, individual).is_some_and(|century| is_date(century + year, month, day))
+}
+
+/// From the individual number ranges Skatteetaten hands out per birth year
+const fn century(year: u32, individual: u32) -> Option<u32> {
+	match individual {
+		0..=499 => Some(1900),
+		500..=749 if year >= 54 => Some(1800),
+		500..=999 if year < 40 => Some(2000),
+		900..=999 => Some(1900),
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_is_valid() {
+		for valid in [
+			"15019012317", // fødselsnummer
+			"55019012300", // D-number
+			"15519012348", // H-number
+			"15919012320", // synthetic
+			"29020050088", // 29 February 2000
+		] {
+			assert!(is_valid(valid), "{valid}");
+		}
+		for invalid in [
+			"12345678901", // bad control digits
+			"15019012318", // second control digit off by one
+			"23031510135", // first control digit would be 10
+			"32019012345", // no 32nd
+			"29021012345", // 1910 wasn't a leap year
+			"1501901231",  // too short
+			"1501901231x",
+		] {
+			assert!(!is_valid(invalid), "{invalid}");
+		}
+	}
+}
@@ -0,0 +1,210 @@
+use std::net::{IpAddr, SocketAddr};
+
+use pingora::http::RequestHeader;
+use pingora::Result;
+
+use super::redact::Redactor;
+use crate::config::{ClientIpAction, HeaderPolicy, RefererAction};
+
+/// Applies `headers` to the client's request headers, before they go upstream
+pub fn apply(policy: &HeaderPolicy, redactor: &Redactor, req: &mut RequestHeader) -> Result<()> {
+	let names: Vec<_> = req.headers.keys().cloned().collect();
+	for name in names {
+		let listed = |names: &[String]| names.iter().any(|n| n.eq_ignore_ascii_case(name.as_str()));
+		if listed(&policy.drop) || (!policy.allow.is_empty() && !listed(&policy.allow)) {
+			req.remove_header(&name);
+		}
+	}
+
+	match policy.referer {
+		RefererAction::Keep => {},
+		RefererAction::Redact => {
+			rewrite(req, "referer", |url| Some(redactor.redact_bare_url(url)))?
+		},
+		RefererAction::Drop => {
+			req.remove_header("referer");
+		},
+	}
+
+	for name in &policy.client_ip_headers {
+		let name = name.to_ascii_lowercase();
+		match policy.client_ip {
+			ClientIpAction::Keep => {},
+			ClientIpAction::Truncate if name == "forwarded" => {
+				rewrite(req, name, truncate_forwarded)?
+			},
+			ClientIpAction::Truncate => rewrite(req, name, |list| {
+				let addrs: Vec<String> = list
+					.split(',')
+					.filter_map(truncate)
+					.map(|addr| addr.to_string())
+					.collect();
+				(!addrs.is_empty()).then(|| addrs.join(", "))
+			})?,
+			ClientIpAction::Drop => {
+				req.remove_header(&name);
+			},
+		}
+	}
+	Ok(())
+}
+
+/// Replaces every value of the header with what `f` makes of it. Values `f` returns `None` for,
+/// or that aren't text, are dropped
+fn rewrite(
+	req: &mut RequestHeader,
+	name: impl Into<String>,
+	f: impl Fn(&str) -> Option<String>,
+) -> Result<()> {
+	let name = name.into();
+	let values: Vec<String> = req
+		.headers
+		.get_all(&name)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.filter_map(f)
+		.collect();
+	if req.remove_header(&name).is_none() {
+		return Ok(());
+	}
+	for value in values {
+		req.append_header(name.clone(), value)?;
+	}
+	Ok(())
+}
+
+/// The /24 of an IPv4 address or the /48 of an IPv6 one, without the port
+fn truncate(addr: &str) -> Option<IpAddr> {
+	let addr = addr.trim().trim_matches('"');
+	let ip = addr
+		.parse::<IpAddr>()
+		.or_else(|_| addr.parse::<SocketAddr>().map(|addr| addr.ip()))
+		.or_else(|_| {
+			addr.strip_prefix('[')
+				.and_then(|addr| addr.strip_suffix(']'))
+				.unwrap_or(addr)
+				.parse::<IpAddr>()
+		})
+		.ok()?;
+	Some(match ip {
+		IpAddr::V4(ip) => {
+			let [a, b, c, _] = ip.octets();
+			IpAddr::from([a, b, c, 0])
+		},
+		IpAddr::V6(ip) => {
+			let [a, b, c, ..] = ip.segments();
+			IpAddr::from([a, b, c, 0, 0, 0, 0, 0])
+		},
+	})
+}
+
+/// `Forwarded: for=192.0.2.60;proto=https, for="[2001:db8::1]:4711"`. Only the `for` parameters
+/// change, and the ones that aren't addresses (`unknown`, obfuscated identifiers) are dropped
+fn truncate_forwarded(value: &str) -> Option<String> {
+	let elements: Vec<String> = value
+		.split(',')
+		.filter_map(|element| {
+			let pairs: Vec<String> = element
+				.split(';')
+				.filter_map(|pair| {
+					let pair = pair.trim();
+					match pair.split_once('=') {
+						Some((key, addr)) if key.eq_ignore_ascii_case("for") => {
+							truncate(addr).map(|addr| match addr {
+								IpAddr::V4(addr) => format!("{key}={addr}"),
+								IpAddr::V6(addr) => format!("{key}=\"[{addr}]\""),
+							})
+						},
+						_ if pair.is_empty() => None,
+						_ => Some(pair.to_owned()),
+					}
+				})
+				.collect();
+			(!pairs.is_empty()).then(|| pairs.join(";"))
+		})
+		.collect();
+	(!elements.is_empty()).then(|| elements.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	fn request(headers: &[(&'static str, &'static str)]) -> RequestHeader {
+		let mut req = RequestHeader::build("POST", b"/api/send", None).unwrap();
+		for (name, value) in headers {
+			req.append_header(*name, *value).unwrap();
+		}
+		req
+	}
+
+	fn values(req: &RequestHeader, name: &str) -> Vec<String> {
+		req.headers
+			.get_all(name)
+			.iter()
+			.map(|v| v.to_str().unwrap().to_owned())
+			.collect()
+	}
+
+	#[test]
+	fn test_default_policy() {
+		let mut req = request(&[
+			("Content-Type", "application/json"),
+			("Cookie", "session=hemmelig"),
+			("Authorization", "Bearer hemmelig"),
+			("Referer", "https://www.nav.no/minside/ola.nordmann@nav.no?fnr=15019012317"),
+			("X-Forwarded-For", "203.0.113.195:41237, 2001:db8:85a3:8d3:1319:8a2e:370:7348"),
+			("X-Forwarded-For", "unknown"),
+			("X-Real-IP", "203.0.113.195"),
+			(
+				"Forwarded",
+				"for=192.0.2.60;proto=https;by=203.0.113.43, for=\"[2001:db8:cafe::17]:4711\", for=_hidden",
+			),
+		]);
+		apply(&HeaderPolicy::default(), &Redactor::default(), &mut req).unwrap();
+
+		assert_eq!(values(&req, "content-type"), vec!["application/json"]);
+		assert!(values(&req, "cookie").is_empty());
+		assert!(values(&req, "authorization").is_empty());
+		assert_eq!(
+			values(&req, "referer"),
+			vec!["https://www.nav.no/minside/[PROXY-EMAIL]?fnr=[PROXY-FNR]"]
+		);
+		assert_eq!(
+			values(&req, "x-forwarded-for"),
+			vec!["203.0.113.0, 2001:db8:85a3::"]
+		);
+		assert_eq!(values(&req, "x-real-ip"), vec!["203.0.113.0"]);
+		assert_eq!(
+			values(&req, "forwarded"),
+			vec!["for=192.0.2.0;proto=https;by=203.0.113.43, for=\"[2001:db8:cafe::]\""]
+		);
+	}
+
+	#[test]
+	fn test_allow_and_drop() {
+		let policy = HeaderPolicy {
+			allow: vec![
+				"Content-Type".into(),
+				"User-Agent".into(),
+				"X-Real-IP".into(),
+			],
+			referer: RefererAction::Drop,
+			client_ip: ClientIpAction::Drop,
+			..HeaderPolicy::default()
+		};
+		let mut req = request(&[
+			("Content-Type", "application/json"),
+			("user-agent", "Mozilla/5.0"),
+			("Referer", "https://www.nav.no/"),
+			("X-Real-IP", "203.0.113.195"),
+			("Accept-Language", "nb-NO"),
+		]);
+		apply(&policy, &Redactor::default(), &mut req).unwrap();
+
+		let mut names: Vec<_> = req.headers.keys().map(|n| n.as_str()).collect();
+		names.sort_unstable();
+		assert_eq!(names, vec!["content-type", "user-agent"]);
+	}
+}
@@ -0,0 +1,204 @@
+use std::collections::HashSet;
+
+use crate::config::{KeyList, KeyMatch};
+
+/// The keys of any number of `KeyList`s, whatever their matching
+#[derive(Clone, Debug, Default)]
+pub struct KeySet {
+	exact: HashSet<String>,
+	/// Lowercased
+	case_insensitive: HashSet<String>,
+	globs: Vec<String>,
+	/// Lowercased, without separators
+	fuzzy: HashSet<String>,
+	/// Words that can come in front of a fuzzy key, like `fuzzy`
+	prefixes: HashSet<String>,
+}
+
+impl KeySet {
+	pub fn new(list: &KeyList) -> Self {
+		let mut set = Self::default();
+		set.extend(list);
+		set
+	}
+
+	/// Lets the fuzzy keys have one of `prefixes` in front: with `bruker`, `navn` matches
+	/// `brukerNavn` too
+	pub fn with_prefixes(mut self, prefixes: &[String]) -> Self {
+		self.prefixes
+			.extend(prefixes.iter().map(|prefix| words(prefix).concat()));
+		self
+	}
+
+	pub fn extend(&mut self, list: &KeyList) {
+		let keys = list.keys.iter().cloned();
+		match list.matching {
+			KeyMatch::Exact => self.exact.extend(keys),
+			KeyMatch::CaseInsensitive => self
+				.case_insensitive
+				.extend(keys.map(|key| key.to_lowercase())),
+			KeyMatch::Glob => self.globs.extend(keys),
+			KeyMatch::Fuzzy => self.fuzzy.extend(keys.map(|key| words(&key).concat())),
+		}
+	}
+
+	pub fn contains(&self, key: &str) -> bool {
+		self.exact.contains(key)
+			|| (!self.case_insensitive.is_empty()
+				&& self.case_insensitive.contains(&key.to_lowercase()))
+			|| self.globs.iter().any(|glob| glob_matches(glob, key))
+			|| (!self.fuzzy.is_empty() && {
+				let words = words(key);
+				self.fuzzy.contains(&words.concat())
+					|| (1..words.len()).any(|i| {
+						self.prefixes.contains(&words[..i].concat())
+							&& self.fuzzy.contains(&words[i..].concat())
+					})
+			})
+	}
+}
+
+/// `key` split at separators and camelCase humps, lowercased: `kontakt_ePost` and `URLPath` are
+/// `[kontakt, e, post]` and `[url, path]`
+fn words(key: &str) -> Vec<String> {
+	let chars: Vec<char> = key.chars().collect();
+	let mut words = Vec::new();
+	let mut word = String::new();
+	for (i, &c) in chars.iter().enumerate() {
+		if !c.is_alphanumeric() {
+			if !word.is_empty() {
+				words.push(std::mem::take(&mut word));
+			}
+			continue;
+		}
+		let hump = c.is_uppercase()
+			&& i > 0 && (chars[i - 1].is_lowercase()
+			|| chars[i - 1].is_numeric()
+			|| (chars[i - 1].is_uppercase() && chars.get(i + 1).is_some_and(|c| c.is_lowercase())));
+		if hump && !word.is_empty() {
+			words.push(std::mem::take(&mut word));
+		}
+		word.extend(c.to_lowercase());
+	}
+	if !word.is_empty() {
+		words.push(word);
+	}
+	words
+}
+
+/// `*` matches any run of characters, `?` any one character
+fn glob_matches(glob: &str, key: &str) -> bool {
+	let glob: Vec<char> = glob.chars().collect();
+	let key: Vec<char> = key.chars().collect();
+	let (mut g, mut k) = (0, 0);
+	// Where the last `*` was, and where in the key it started matching
+	let mut star: Option<(usize, usize)> = None;
+	while k < key.len() {
+		match glob.get(g) {
+			Some('*') => {
+				star = Some((g, k));
+				g += 1;
+			},
+			Some(&c) if c == '?' || c == key[k] => {
+				g += 1;
+				k += 1;
+			},
+			// Let the last `*` eat one more character and try again
+			_ => match star {
+				Some((star_g, star_k)) => {
+					star = Some((star_g, star_k + 1));
+					g = star_g + 1;
+					k = star_k + 1;
+				},
+				None => return false,
+			},
+		}
+	}
+	glob[g..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn list(matching: KeyMatch, keys: &[&str]) -> KeyList {
+		KeyList {
+			matching,
+			keys: keys.iter().map(ToString::to_string).collect(),
+		}
+	}
+
+	#[test]
+	fn test_matching() {
+		let mut keys = KeySet::new(&list(KeyMatch::Exact, &["lenketekst"]));
+		keys.extend(&list(KeyMatch::CaseInsensitive, &["tlbhrNavn"]));
+		keys.extend(&list(KeyMatch::Glob, &["url_*", "*Location", "fra?"]));
+
+		for key in [
+			"lenketekst",
+			"tlbhrnavn",
+			"TLBHRNAVN",
+			"url_path",
+			"url_",
+			"prevLocation",
+			"fraA",
+		] {
+			assert!(keys.contains(key), "{key}");
+		}
+		for key in [
+			"Lenketekst",
+			"tlbhrNavnX",
+			"url",
+			"my_url_path",
+			"prevlocation",
+			"fra",
+			"fraAB",
+		] {
+			assert!(!keys.contains(key), "{key}");
+		}
+	}
+
+	#[test]
+	fn test_fuzzy() {
+		let keys = KeySet::new(&list(
+			KeyMatch::Fuzzy,
+			&["epost", "e-mail", "telefon", "navn"],
+		))
+		.with_prefixes(&["kontakt".into(), "bruker".into()]);
+		for key in [
+			"epost",
+			"ePost",
+			"e_post",
+			"kontaktEpost",
+			"bruker-e-post",
+			"EMAIL",
+			"Telefon",
+			"brukerNavn",
+		] {
+			assert!(keys.contains(key), "{key}");
+		}
+		for key in [
+			"epostVarsel",
+			"eposten",
+			"emails",
+			"telefonkatalogen",
+			"mobiltelefon",
+			"mobil_telefon",
+			"userEMail",
+			"skjemaNavn",
+			"lenke_navn",
+		] {
+			assert!(!keys.contains(key), "{key}");
+		}
+		assert_eq!(words("URLPath2Go"), vec!["url", "path2", "go"]);
+	}
+
+	#[test]
+	fn test_glob_backtracks() {
+		assert!(glob_matches("*a*b", "xaxaxb"));
+		assert!(glob_matches("a*", "a"));
+		assert!(glob_matches("**", ""));
+		assert!(!glob_matches("*a*b", "xaxbxa"));
+		assert!(!glob_matches("?", ""));
+	}
+}
@@ -0,0 +1,420 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+
+static DEFAULT_FIRST_NAMES: Lazy<Arc<NameList>> = Lazy::new(|| {
+	Arc::new(
+		parse(include_str!("../../conf/names/first-names.txt"))
+			.expect("The built-in first names should be valid"),
+	)
+});
+static DEFAULT_SURNAMES: Lazy<Arc<NameList>> = Lazy::new(|| {
+	Arc::new(
+		parse(include_str!("../../conf/names/surnames.txt"))
+			.expect("The built-in surnames should be valid"),
+	)
+});
+static COMMON_WORDS: Lazy<HashSet<String>> = Lazy::new(|| {
+	include_str!("../../conf/names/common-words.txt")
+		.lines()
+		.map(str::trim)
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(str::to_lowercase)
+		.collect()
+});
+/// Letters (with combining marks, for Sami written that way), digits and what joins the parts of
+/// a name: `Anne-Marie`, `O'Brien`
+static WORD_REGEX: Lazy<Regex> =
+	Lazy::new(|| Regex::new(r"[\w'’-]+").expect("Hard-coded regex expression should be valid"));
+
+/// Lowercased name to how sure we are it's one, from 0.25 (a handful of people) to 1 (10 000 or more)
+type NameList = HashMap<String, f64>;
+
+/// Longest run of words taken as one name
+const MAX_WORDS: usize = 4;
+/// A name on its own could as well be a word, a place or a brand
+const SINGLE_WORD: f64 = 0.7;
+/// A capitalized word we don't know right before a surname (`Zelinda Hansen`) or after a first
+/// name (`John Doe`). There are too many names to list them all
+const UNKNOWN_NAME: f64 = 0.35;
+
+/// `names` in a rules file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NameRule {
+	#[serde(default = "default_threshold")]
+	threshold: f64,
+	first_names: Option<PathBuf>,
+	surnames: Option<PathBuf>,
+}
+
+fn default_threshold() -> f64 {
+	0.6
+}
+
+/// Finds names by looking their words up in first-name and surname lists
+#[derive(Clone)]
+pub struct NameDetector {
+	first_names: Arc<NameList>,
+	surnames: Arc<NameList>,
+	threshold: f64,
+}
+
+impl fmt::Debug for NameDetector {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("NameDetector")
+			.field("first_names", &self.first_names.len())
+			.field("surnames", &self.surnames.len())
+			.field("threshold", &self.threshold)
+			.finish()
+	}
+}
+
+impl NameDetector {
+	pub fn new(rule: NameRule) -> Result<Self, Vec<String>> {
+		let mut errors = Vec::new();
+		if !(rule.threshold > 0.0 && rule.threshold <= 1.0) {
+			errors.push(format!(
+				"threshold must be above 0 and at most 1, not {}",
+				rule.threshold
+			));
+		}
+		let mut load = |path: Option<&Path>, default: &Lazy<Arc<NameList>>| {
+			let Some(path) = path else {
+				return Arc::clone(default);
+			};
+			fs::read_to_string(path)
+				.map_err(|e| e.to_string())
+				.and_then(|list| parse(&list))
+				.map(Arc::new)
+				.unwrap_or_else(|e| {
+					errors.push(format!("{}: {e}", path.display()));
+					Arc::default()
+				})
+		};
+		let first_names = load(rule.first_names.as_deref(), &DEFAULT_FIRST_NAMES);
+		let surnames = load(rule.surnames.as_deref(), &DEFAULT_SURNAMES);
+		if !errors.is_empty() {
+			return Err(errors);
+		}
+		Ok(Self {
+			first_names,
+			surnames,
+			threshold: rule.threshold,
+		})
+	}
+
+	/// Where the names in `s` are. Runs of up to four words are scored, longest first, and the
+	/// first run to reach the threshold is a name
+	pub fn find(&self, s: &str) -> Vec<Range<usize>> {
+		let mut names = Vec::new();
+		for group in groups(s) {
+			let mut i = 0;
+			while i < group.len() {
+				let longest = MAX_WORDS.min(group.len() - i);
+				match (1..=longest)
+					.rev()
+					.find(|n| self.score(&group[i..i + n]) >= self.threshold)
+				{
+					Some(n) => {
+						names.push(group[i].range.start..group[i + n - 1].range.end);
+						i += n;
+					},
+					None => i += 1,
+				}
+			}
+		}
+		names
+	}
+
+	/// How much `words` look like one person's name, from 0 to 1. It has to start with a first
+	/// name or end with a surname, and the other end can be any capitalized word. The words in
+	/// between have to be names too. A word on its own can be either kind of name
+	fn score(&self, words: &[Word]) -> f64 {
+		let scores: Option<Vec<f64>> = match words {
+			[] => None,
+			[word] => self
+				.first_name(word)
+				.into_iter()
+				.chain(self.surname(word))
+				.reduce(f64::max)
+				.map(|score| vec![score * SINGLE_WORD]),
+			[first, middle @ .., last] => {
+				let unknown = |word: &Word| {
+					(word.case == Case::Title && !COMMON_WORDS.contains(&word.lower))
+						.then_some(UNKNOWN_NAME)
+				};
+				let (first_known, last_known) = (
+					self.first_name(first),
+					self.surname(last).or(self.first_name(last)),
+				);
+				// Only one end may be unknown. An unknown first word only goes right before a
+				// surname, so `Hilsen Aurora Olsen` doesn't take the greeting along
+				let before_surname = middle.is_empty() && self.surname(last).is_some();
+				let first =
+					first_known.or_else(|| before_surname.then(|| unknown(first)).flatten());
+				let last = last_known.or(first_known.and(unknown(last)));
+				let middle = middle.iter().map(|word| {
+					self.first_name(word)
+						.into_iter()
+						.chain(self.surname(word))
+						.reduce(f64::max)
+				});
+				[first].into_iter().chain(middle).chain([last]).collect()
+			},
+		};
+		let Some(scores) = scores else {
+			return 0.0;
+		};
+		let case = words
+			.iter()
+			.map(|word| word.case.weight())
+			.fold(1.0, f64::min);
+		scores.iter().sum::<f64>() / scores.len() as f64 * case
+	}
+
+	fn first_name(&self, word: &Word) -> Option<f64> {
+		lookup(&self.first_names, &word.lower)
+	}
+
+	fn surname(&self, word: &Word) -> Option<f64> {
+		lookup(&self.surnames, &word.lower)
+	}
+}
+
+/// Names that are everyday words too count for half. A hyphenated word is a name when all its
+/// parts are (`Anne-Marie`, `Hansen-Berg`), and counts as much as the surest part
+fn lookup(list: &NameList, lower: &str) -> Option<f64> {
+	let part = |part: &str| {
+		list.get(part).map(|score| {
+			if COMMON_WORDS.contains(part) {
+				score / 2.0
+			} else {
+				*score
+			}
+		})
+	};
+	part(lower).or_else(|| {
+		if !lower.contains('-') {
+			return None;
+		}
+		lower
+			.split('-')
+			.map(part)
+			.collect::<Option<Vec<_>>>()?
+			.into_iter()
+			.reduce(f64::max)
+	})
+}
+
+/// A list of `name count` lines, `#` starts a comment
+fn parse(list: &str) -> Result<NameList, String> {
+	let mut names = NameList::new();
+	for (i, line) in list.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let (name, count) = line
+			.rsplit_once(char::is_whitespace)
+			.and_then(|(name, count)| Some((name.trim(), count.parse::<u64>().ok()?)))
+			.filter(|(name, count)| !name.is_empty() && *count > 0)
+			.ok_or_else(|| format!("line {}: expected `name count`", i + 1))?;
+		let score = ((count as f64).log10() / 4.0).clamp(0.25, 1.0);
+		names.insert(name.to_lowercase(), score);
+	}
+	Ok(names)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Case {
+	/// `Ola`, `Anne-Marie`
+	Title,
+	Upper,
+	Lower,
+}
+
+impl Case {
+	fn of(word: &str) -> Option<Self> {
+		let letters = || word.chars().filter(|c| c.is_alphabetic());
+		if letters().all(|c| !c.is_uppercase()) {
+			return Some(Self::Lower);
+		}
+		if letters().all(|c| !c.is_lowercase()) && letters().count() > 1 {
+			return Some(Self::Upper);
+		}
+		word.split(['-', '\'', '’'])
+			.all(|part| {
+				let mut chars = part.chars();
+				chars.next().is_some_and(char::is_uppercase) && chars.all(|c| !c.is_uppercase())
+			})
+			.then_some(Self::Title)
+	}
+
+	/// Names are written capitalized, so other casings make a name less likely
+	fn weight(self) -> f64 {
+		match self {
+			Self::Title => 1.0,
+			Self::Upper => 0.9,
+			Self::Lower => 0.8,
+		}
+	}
+}
+
+#[derive(Debug)]
+struct Word {
+	range: Range<usize>,
+	lower: String,
+	case: Case,
+}
+
+impl Word {
+	/// `None` if it has digits or underscores in it, or a casing like `iPhone`
+	fn new(s: &str, start: usize) -> Option<Self> {
+		let trimmed = s.trim_start_matches(['-', '\'', '’']);
+		let start = start + s.len() - trimmed.len();
+		let word = trimmed.trim_end_matches(['-', '\'', '’']);
+		let joined = word
+			.split(['-', '\'', '’'])
+			.all(|part| !part.is_empty() && part.chars().all(|c| !c.is_numeric() && c != '_'));
+		if word.is_empty() || !joined {
+			return None;
+		}
+		Some(Self {
+			range: start..start + word.len(),
+			lower: word.to_lowercase(),
+			case: Case::of(word)?,
+		})
+	}
+}
+
+/// The words in `s`, split wherever anything but spaces comes between two of them or a word can't
+/// be a name, since a name doesn't go across punctuation or line breaks. A `+` is a space, like in
+/// a query string
+fn groups(s: &str) -> Vec<Vec<Word>> {
+	let mut groups = vec![Vec::new()];
+	let mut last = 0;
+	for m in WORD_REGEX.find_iter(s) {
+		let word = Word::new(m.as_str(), m.start());
+		let gap = match &word {
+			Some(word) => &s[last..word.range.start],
+			None => "",
+		};
+		let joined = !gap.is_empty()
+			&& gap
+				.chars()
+				.all(|c| (c.is_whitespace() && c != '\n' && c != '\r') || c == '+');
+		if !joined {
+			groups.push(Vec::new());
+		}
+		if let Some(word) = word {
+			last = word.range.end;
+			groups
+				.last_mut()
+				.expect("There's always a group")
+				.push(word);
+		} else {
+			last = m.end();
+		}
+	}
+	groups.retain(|group| !group.is_empty());
+	groups
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	fn names(s: &str) -> Vec<&str> {
+		let detector = NameDetector::new(NameRule {
+			threshold: default_threshold(),
+			first_names: None,
+			surnames: None,
+		})
+		.unwrap();
+		detector
+			.find(s)
+			.into_iter()
+			.map(|range| &s[range])
+			.collect()
+	}
+
+	#[test]
+	fn test_find() {
+		assert_eq!(names("Mine Saker"), Vec::<&str>::new());
+		assert_eq!(names("Hele Vakre Norge"), Vec::<&str>::new());
+		assert_eq!(names("Ola Nordmann"), vec!["Ola Nordmann"]);
+		assert_eq!(
+			names("Hei, jeg er Ola Per Nordmann."),
+			vec!["Ola Per Nordmann"]
+		);
+		assert_eq!(names("sendt av ole hansen i går"), vec!["ole hansen"]);
+		assert_eq!(names("Skrevet av Kari"), vec!["Kari"]);
+		assert_eq!(names("John Doe"), vec!["John Doe"]);
+		assert_eq!(
+			names("Anne-Marie Hansen-Berg"),
+			vec!["Anne-Marie Hansen-Berg"]
+		);
+		assert_eq!(
+			names("Máret Hætta og Niillas Somby"),
+			vec!["Máret Hætta", "Niillas Somby"]
+		);
+		assert_eq!(names("OLA NORDMANN"), vec!["OLA NORDMANN"]);
+		assert_eq!(names("?navn=Ola+Nordmann"), vec!["Ola+Nordmann"]);
+	}
+
+	#[test]
+	fn test_names_not_in_the_lists() {
+		// A first name we don't know before a surname we do, and the other way around
+		assert_eq!(names("Zelinda Hansen"), vec!["Zelinda Hansen"]);
+		assert_eq!(
+			names("skrevet av Trygve Qvistgaard"),
+			vec!["Trygve Qvistgaard"]
+		);
+		assert_eq!(names("Hilsen Aurora Olsen"), vec!["Aurora Olsen"]);
+		// Unknown at both ends, or next to a surname that's a word too
+		assert_eq!(names("Zelinda Qvistgaard"), Vec::<&str>::new());
+		assert_eq!(names("Velkommen Berg"), Vec::<&str>::new());
+		assert_eq!(names("zelinda hansen"), Vec::<&str>::new());
+	}
+
+	#[test]
+	fn test_words_that_are_names() {
+		// Common words, or names on their own in lowercase
+		assert_eq!(names("Kr 500 per måned"), Vec::<&str>::new());
+		assert_eq!(names("Per Måned"), Vec::<&str>::new());
+		assert_eq!(names("Hans Side"), Vec::<&str>::new());
+		assert_eq!(names("kari"), Vec::<&str>::new());
+		// Not across punctuation or line breaks, nor into identifiers
+		assert_eq!(names("Ola, Nordmann"), vec!["Ola"]);
+		assert_eq!(names("Ola\nNordmann"), vec!["Ola"]);
+		assert_eq!(names("ola_nordmann Ola2"), Vec::<&str>::new());
+	}
+
+	#[test]
+	fn test_name_rule() {
+		let detector = |yaml| {
+			serde_yaml::from_str::<NameRule>(yaml)
+				.map_err(|e| vec![e.to_string()])
+				.and_then(NameDetector::new)
+		};
+		assert_eq!(detector("threshold: 1.5").unwrap_err().len(), 1);
+		assert!(detector("surnames: /nonexistent/surnames.txt").is_err());
+
+		// Only the most common names, in full and capitalized
+		let strict = detector("threshold: 0.9").unwrap();
+		assert_eq!(strict.find("Kari Nordmann og Ole Hansen").len(), 1);
+
+		assert!(parse("Ola").is_err());
+		assert!(parse("# comment\nOla 0").is_err());
+		assert_eq!(parse("# comment\n\nÁile 300").unwrap().len(), 1);
+	}
+}
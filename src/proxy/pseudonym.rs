@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use std::fmt::{self, Debug, Formatter};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use crate::config::Pseudonymize;
+
+// Shorter than any digit run the built-in patterns look for, so a tag never gets redacted itself
+const TAG_LENGTH: usize = 6;
+// `action: hash` replaces whole values, where a longer hash is no risk and collides less
+const HASH_LENGTH: usize = 16;
+const MIN_SECRET_LENGTH: usize = 16;
+
+/// Replaces matches and hashed fields with a truncated HMAC. The key for each period is derived
+/// from the secret, so every replica uses the same key without having to agree on anything
+pub struct Pseudonymizer {
+	labels: HashSet<String>,
+	secret: Vec<u8>,
+	period_secs: u64,
+	/// The current period and its key
+	current: Mutex<(u64, Vec<u8>)>,
+}
+
+impl Pseudonymizer {
+	/// `None` without a `key_file`, which is only allowed when no labels are pseudonymized
+	pub fn new(conf: &Pseudonymize) -> Result<Option<Self>, String> {
+		// `Config::validate` makes sure it's set when there are labels
+		let Some(path) = &conf.key_file else {
+			if conf.labels.is_empty() {
+				return Ok(None);
+			}
+			return Err("key_file is needed to pseudonymize labels".into());
+		};
+		let secret = fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+		// Secrets written by hand tend to end with a newline
+		let secret = secret.trim_ascii().to_vec();
+		if secret.len() < MIN_SECRET_LENGTH {
+			return Err(format!(
+				"{} is shorter than {MIN_SECRET_LENGTH} bytes",
+				path.display()
+			));
+		}
+		// `Config::validate` checks this too
+		let Some(period_secs) = conf.rotation_hours.checked_mul(60 * 60) else {
+			return Err(format!(
+				"rotation_hours {} is too long",
+				conf.rotation_hours
+			));
+		};
+		Ok(Some(Self {
+			labels: conf.labels.clone(),
+			secret,
+			period_secs,
+			current: Mutex::new((u64::MAX, Vec::new())),
+		}))
+	}
+
+	/// With `secret` instead of reading `key_file`
+	#[cfg(test)]
+	pub fn with_secret(conf: &Pseudonymize, secret: &[u8]) -> Self {
+		Self {
+			labels: conf.labels.clone(),
+			secret: secret.to_vec(),
+			period_secs: conf.rotation_hours * 60 * 60,
+			current: Mutex::new((u64::MAX, Vec::new())),
+		}
+	}
+
+	pub fn applies_to(&self, label: &str) -> bool {
+		self.labels.contains(label)
+	}
+
+	/// `[<label>:<hash>]`
+	pub fn tag(&self, label: &str, value: &str) -> String {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		self.tag_at(label, value, now)
+	}
+
+	/// `[PROXY-HASH:<hash>]`, for `action: hash`
+	pub fn hash(&self, value: &str) -> String {
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.unwrap_or_default()
+			.as_secs();
+		let hash = hex(&self.digest_at("PROXY-HASH", value, now), HASH_LENGTH);
+		format!("[PROXY-HASH:{hash}]")
+	}
+
+	fn tag_at(&self, label: &str, value: &str, now: u64) -> String {
+		let hash = hex(&self.digest_at(label, value, now), TAG_LENGTH);
+		format!("[{label}:{hash}]")
+	}
+
+	fn digest_at(&self, label: &str, value: &str, now: u64) -> Vec<u8> {
+		let period = now / self.period_secs;
+		let key = {
+			let mut current = self.current.lock().expect("Failed to lock pseudonym key");
+			if current.0 != period {
+				*current = (period, hmac(&self.secret, &period.to_be_bytes()));
+			}
+			current.1.clone()
+		};
+		// The label goes into the hash, so an FNR and an account number with the same digits
+		// can't be linked
+		hmac(&key, &[label.as_bytes(), b"\0", value.as_bytes()].concat())
+	}
+}
+
+impl Debug for Pseudonymizer {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		f.debug_struct("Pseudonymizer")
+			.field("labels", &self.labels)
+			.field("period_secs", &self.period_secs)
+			.finish_non_exhaustive()
+	}
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+	PKey::hmac(key)
+		.and_then(|key| {
+			let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+			signer.update(data)?;
+			signer.sign_to_vec()
+		})
+		.expect("HMAC-SHA256 with an in-memory key should never fail")
+}
+
+/// The first `length` hex digits
+fn hex(digest: &[u8], length: usize) -> String {
+	digest
+		.iter()
+		.take(length / 2)
+		.map(|b| format!("{b:02x}"))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	fn pseudonymizer() -> Pseudonymizer {
+		let conf = Pseudonymize {
+			labels: HashSet::from(["PROXY-EMAIL".into()]),
+			rotation_hours: 1,
+			..Pseudonymize::default()
+		};
+		Pseudonymizer::with_secret(&conf, b"0123456789abcdef")
+	}
+
+	#[test]
+	fn test_same_value_same_tag_within_a_period() {
+		let p = pseudonymizer();
+		let tag = p.tag_at("PROXY-EMAIL", "ola@nav.no", 0);
+		assert!(tag.starts_with("[PROXY-EMAIL:") && tag.len() == 20, "{tag}");
+		assert_eq!(p.tag_at("PROXY-EMAIL", "ola@nav.no", 3599), tag);
+		assert_ne!(p.tag_at("PROXY-EMAIL", "kari@nav.no", 0), tag);
+		assert_ne!(
+			p.tag_at("PROXY-EMAIL", "ola@nav.no", 3600),
+			tag,
+			"The key rotates"
+		);
+		assert_eq!(
+			p.tag_at("PROXY-EMAIL", "ola@nav.no", 0),
+			tag,
+			"Going back to a period derives the same key"
+		);
+	}
+
+	#[test]
+	fn test_label_is_part_of_the_hash() {
+		let p = pseudonymizer();
+		let fnr = p.tag_at("PROXY-FNR", "12345678901", 0);
+		let account = p.tag_at("PROXY-ACCOUNT", "12345678901", 0);
+		assert_ne!(fnr[fnr.len() - 7..], account[account.len() - 7..]);
+	}
+}
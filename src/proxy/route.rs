@@ -0,0 +1,127 @@
+use crate::config::Route;
+
+/// The parts of a request that routes can match on. The body fields are only looked up when a
+/// route actually needs them
+#[derive(Debug, Default)]
+pub struct RouteKey<'a> {
+	pub origin: &'a str,
+	pub path: &'a str,
+	pub website_id: Option<&'a str>,
+	pub namespace: Option<&'a str>,
+}
+
+/// Name of the upstream picked by the first matching route, `None` means the default upstream
+pub fn select<'a>(routes: &'a [Route], key: &RouteKey) -> Option<&'a str> {
+	routes
+		.iter()
+		.find(|route| matches(route, key))
+		.map(|route| route.upstream.as_str())
+}
+
+fn matches(route: &Route, key: &RouteKey) -> bool {
+	let any_of = |values: &[String], found: Option<&str>| {
+		values.is_empty() || found.is_some_and(|found| values.iter().any(|v| v == found))
+	};
+
+	(route.origins.is_empty()
+		|| route
+			.origins
+			.iter()
+			.any(|pattern| origin_matches(pattern, key.origin)))
+		&& (route.path_prefixes.is_empty()
+			|| route
+				.path_prefixes
+				.iter()
+				.any(|prefix| key.path.starts_with(prefix.as_str())))
+		&& any_of(&route.website_ids, key.website_id)
+		&& any_of(&route.namespaces, key.namespace)
+}
+
+/// `*.nav.no` matches any subdomain of nav.no, but not nav.no itself
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+	let host = origin.split(':').next().unwrap_or_default();
+	match pattern.strip_prefix("*.") {
+		Some(domain) => host
+			.len()
+			.checked_sub(domain.len())
+			.and_then(|split| Some((host.get(..split)?, host.get(split..)?)))
+			.is_some_and(|(subdomain, rest)| {
+				subdomain.len() > 1 && subdomain.ends_with('.') && rest.eq_ignore_ascii_case(domain)
+			}),
+		None => host.eq_ignore_ascii_case(pattern),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	fn route(upstream: &str) -> Route {
+		Route {
+			upstream: upstream.into(),
+			..Route::default()
+		}
+	}
+
+	#[test]
+	fn test_origin_wildcards() {
+		assert!(origin_matches("*.intern.nav.no", "app.intern.nav.no"));
+		assert!(origin_matches("*.intern.nav.no", "app.INTERN.nav.no:443"));
+		assert!(!origin_matches("*.intern.nav.no", "intern.nav.no"));
+		assert!(!origin_matches("*.intern.nav.no", "appintern.nav.no"));
+		assert!(origin_matches("www.nav.no", "www.nav.no"));
+		assert!(!origin_matches("www.nav.no", "missing origin"));
+	}
+
+	#[test]
+	fn test_first_matching_route_wins() {
+		let routes = vec![
+			Route {
+				origins: vec!["*.intern.nav.no".into()],
+				path_prefixes: vec!["/api/send".into()],
+				..route("internal")
+			},
+			Route {
+				namespaces: vec!["team-canary".into()],
+				..route("beta")
+			},
+			Route {
+				website_ids: vec!["c2a9e3d1".into()],
+				..route("beta-website")
+			},
+		];
+
+		let internal = RouteKey {
+			origin: "app.intern.nav.no",
+			path: "/api/send",
+			namespace: Some("team-canary"),
+			..RouteKey::default()
+		};
+		assert_eq!(select(&routes, &internal), Some("internal"));
+
+		let wrong_path = RouteKey {
+			path: "/api/collect",
+			..internal
+		};
+		assert_eq!(
+			select(&routes, &wrong_path),
+			Some("beta"),
+			"Every criterion a route sets has to match"
+		);
+
+		let website = RouteKey {
+			origin: "www.nav.no",
+			website_id: Some("c2a9e3d1"),
+			..RouteKey::default()
+		};
+		assert_eq!(select(&routes, &website), Some("beta-website"));
+
+		let unrouted = RouteKey {
+			origin: "www.nav.no",
+			path: "/api/send",
+			..RouteKey::default()
+		};
+		assert_eq!(select(&routes, &unrouted), None);
+	}
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::Value;
+use tracing::info;
+
+use super::redact::{AppPolicy, Redactor};
+use crate::config::{Redaction, Violation};
+use crate::metrics::{SHADOW_DIFFERENCES, SHADOW_EVENTS};
+
+/// `[PROXY-EMAIL]`, or `[PROXY-EMAIL:3f9a1c]` when pseudonymized
+static LABEL_REGEX: Lazy<Regex> = Lazy::new(|| {
+	Regex::new(r"\[([^\[\]\s:]+)(?::[0-9a-f]+)?\]")
+		.expect("Hard-coded regex expression should be valid")
+});
+
+/// `redaction.shadow`, the candidate rule set and how often to log its differences
+#[derive(Debug)]
+pub struct Shadow {
+	candidate: Redactor,
+	log_every: u64,
+	differing_events: AtomicU64,
+}
+
+impl Shadow {
+	/// `None` when there's no candidate. It shares everything but the patterns with the active
+	/// redaction, so only the patterns make a difference
+	pub fn new(conf: &Redaction) -> Result<Option<Self>, Vec<Violation>> {
+		let Some(shadow) = &conf.shadow else {
+			return Ok(None);
+		};
+		let candidate = Redaction {
+			rules_file: Some(shadow.rules_file.clone()),
+			disabled_labels: shadow
+				.disabled_labels
+				.clone()
+				.unwrap_or_else(|| conf.disabled_labels.clone()),
+			shadow: None,
+			..conf.clone()
+		};
+		let candidate = Redactor::new(&candidate).map_err(|violations| {
+			violations
+				.into_iter()
+				.map(|v| match v.field.as_str() {
+					"redaction.rules_file" => {
+						Violation::new("redaction.shadow.rules_file", v.reason)
+					},
+					"redaction.disabled_labels" if shadow.disabled_labels.is_some() => {
+						Violation::new("redaction.shadow.disabled_labels", v.reason)
+					},
+					field => Violation::new(
+						"redaction.shadow.rules_file",
+						format!("doesn't fit `{field}`: {}", v.reason),
+					),
+				})
+				.collect::<Vec<_>>()
+		})?;
+		Ok(Some(Self {
+			candidate,
+			log_every: shadow.log_every,
+			differing_events: AtomicU64::new(0),
+		}))
+	}
+
+	/// Redacts `event` with the candidate and counts where it differs from `redacted`, `active`'s
+	/// output. Only field paths are logged, never values, and the keys in them go through both
+	/// redactions
+	pub fn compare(
+		&self,
+		mut event: Value,
+		redacted: &Value,
+		active: &Redactor,
+		policy: Option<&AppPolicy>,
+		app: &str,
+	) {
+		let website_id = super::get_website_id(&event);
+		let active = active.for_event(website_id, policy);
+		let candidate = self.candidate.for_event(website_id, policy);
+		candidate.traverse_and_redact(&mut event);
+
+		let known: HashSet<&str> = active.labels().chain(candidate.labels()).collect();
+		let key = |key: &str| candidate.redact_key(&active.redact_key(key));
+		let mut differences = Vec::new();
+		diff(redacted, &event, "", &known, &key, &mut differences);
+		if differences.is_empty() {
+			SHADOW_EVENTS.with_label_values(&[app, "same"]).inc();
+			return;
+		}
+		SHADOW_EVENTS.with_label_values(&[app, "different"]).inc();
+		for difference in &differences {
+			for label in &difference.labels {
+				SHADOW_DIFFERENCES.with_label_values(&[app, label]).inc();
+			}
+		}
+
+		if self
+			.differing_events
+			.fetch_add(1, Ordering::Relaxed)
+			.is_multiple_of(self.log_every)
+		{
+			let paths: Vec<&str> = differences.iter().map(|d| d.path.as_str()).collect();
+			info!(
+				"shadow redaction differs for app {app} at: {}",
+				paths.join(", ")
+			);
+		}
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Difference {
+	/// Like the paths of `redaction.field_rules`
+	path: String,
+	/// The labels that one of the two put in and the other didn't, `none` if there are none.
+	/// Bracketed text that isn't one of the patterns' labels counts as `other`
+	labels: BTreeSet<String>,
+}
+
+/// `known` are the labels of both pattern sets, `key` is what a key looks like in the path
+fn diff(
+	active: &Value,
+	candidate: &Value,
+	path: &str,
+	known: &HashSet<&str>,
+	key: &dyn Fn(&str) -> String,
+	differences: &mut Vec<Difference>,
+) {
+	match (active, candidate) {
+		(Value::Object(active), Value::Object(candidate)) => {
+			let keys: BTreeSet<&String> = active.keys().chain(candidate.keys()).collect();
+			for name in keys {
+				let path = if path.is_empty() {
+					key(name)
+				} else {
+					format!("{path}.{}", key(name))
+				};
+				match (active.get(name), candidate.get(name)) {
+					(Some(a), Some(c)) => diff(a, c, &path, known, key, differences),
+					(a, c) => differences.push(Difference {
+						labels: labels(a.unwrap_or(&Value::Null), c.unwrap_or(&Value::Null), known),
+						path,
+					}),
+				}
+			}
+		},
+		(Value::Array(a), Value::Array(c)) if a.len() == c.len() => {
+			for (i, (a, c)) in a.iter().zip(c).enumerate() {
+				diff(a, c, &format!("{path}[{i}]"), known, key, differences);
+			}
+		},
+		(a, c) if a == c => {},
+		(a, c) => differences.push(Difference {
+			path: path.into(),
+			labels: labels(a, c, known),
+		}),
+	}
+}
+
+fn labels(active: &Value, candidate: &Value, known: &HashSet<&str>) -> BTreeSet<String> {
+	let count = |value: &Value| {
+		let text = match value {
+			Value::String(s) => s.clone(),
+			other => other.to_string(),
+		};
+		let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+		for caps in LABEL_REGEX.captures_iter(&text) {
+			// Anything else in brackets could be a value, and would make the metric's labels endless
+			let label = if known.contains(&caps[1]) {
+				&caps[1]
+			} else {
+				"other"
+			};
+			*counts.entry(label.to_owned()).or_default() += 1;
+		}
+		counts
+	};
+	let (active, candidate) = (count(active), count(candidate));
+	let all: BTreeSet<String> = active.keys().chain(candidate.keys()).cloned().collect();
+	let changed: BTreeSet<String> = all
+		.iter()
+		.filter(|label| active.get(*label) != candidate.get(*label))
+		.cloned()
+		.collect();
+	// Same labels in different places, e.g. a match that grew
+	match (changed.is_empty(), all.is_empty()) {
+		(false, _) => changed,
+		(true, false) => all,
+		(true, true) => BTreeSet::from(["none".to_owned()]),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+
+	#[test]
+	fn test_candidate_violations_are_its_own() {
+		let conf = Redaction {
+			shadow: Some(crate::config::Shadow {
+				rules_file: "/nonexistent/candidate-rules.yaml".into(),
+				disabled_labels: None,
+				log_every: 1,
+			}),
+			..Redaction::default()
+		};
+		let violations = Shadow::new(&conf).unwrap_err();
+		assert_eq!(violations.len(), 1);
+		assert_eq!(violations[0].field, "redaction.shadow.rules_file");
+		assert!(Shadow::new(&Redaction::default()).unwrap().is_none());
+	}
+
+	#[test]
+	fn test_diff_paths_and_labels() {
+		let active = json!({
+			"payload": {
+				"title": "Hei [PROXY-EMAIL]",
+				"data": [{ "phone": "[PROXY-PHONE]" }, "[PROXY-FNR:3f9a1c]"],
+				"flags": {},
+				"url": "/same",
+			}
+		});
+		let candidate = json!({
+			"payload": {
+				"title": "Hei [ola@nav.no]",
+				"data": [{ "phone": "[PROXY-PHONE]0" }, "[PROXY-ACCOUNT]"],
+				"flags": { "kari@nav.no": true },
+				"url": "/same",
+			}
+		});
+		let known = HashSet::from(["PROXY-PHONE", "PROXY-FNR", "PROXY-ACCOUNT", "PROXY-EMAIL"]);
+		let redactor = Redactor::default();
+		let key = |key: &str| redactor.redact_key(key);
+		let mut differences = Vec::new();
+		diff(&active, &candidate, "", &known, &key, &mut differences);
+		let labels = |labels: &[&str]| labels.iter().map(ToString::to_string).collect();
+		assert_eq!(
+			differences,
+			vec![
+				Difference {
+					path: "payload.data[0].phone".into(),
+					labels: labels(&["PROXY-PHONE"]),
+				},
+				Difference {
+					path: "payload.data[1]".into(),
+					labels: labels(&["PROXY-ACCOUNT", "PROXY-FNR"]),
+				},
+				Difference {
+					path: "payload.flags.[PROXY-EMAIL]".into(),
+					labels: labels(&["none"]),
+				},
+				// Not a label, but it could be a value
+				Difference {
+					path: "payload.title".into(),
+					labels: labels(&["PROXY-EMAIL", "other"]),
+				},
+			]
+		);
+	}
+}
@@ -1,9 +1,7 @@
 use serde_json::Value;
 
-const MAX_FIELD_LENGTH: usize = 500;
 const TRUNCATION_MARKER: &str = "TRUNCATED";
 const TRUNCATION_MARKER_LENGTH: usize = TRUNCATION_MARKER.len(); // 9 characters
-const MAX_CONTENT_LENGTH: usize = MAX_FIELD_LENGTH - TRUNCATION_MARKER_LENGTH; // 491 characters
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FieldViolation {
@@ -17,12 +15,13 @@ impl FieldViolation {
 	}
 }
 
-/// Validates and truncates fields that exceed the maximum length.
+/// Validates and truncates fields that exceed `max_length`.
 /// Returns a tuple of (truncated_value, violations).
-/// The truncated value has all offending fields truncated to 491 characters with "TRUNCATED" appended.
-pub fn validate_and_filter(value: &Value) -> (Value, Vec<FieldViolation>) {
+/// The truncated value has all offending fields truncated so that, with "TRUNCATED" appended,
+/// they are exactly `max_length` characters (491 + 9 with the default limit of 500).
+pub fn validate_and_filter(value: &Value, max_length: usize) -> (Value, Vec<FieldViolation>) {
 	let mut violations = Vec::new();
-	let truncated = truncate_long_fields(value, String::new(), &mut violations);
+	let truncated = truncate_long_fields(value, String::new(), max_length, &mut violations);
 	(truncated, violations)
 }
 
@@ -31,16 +30,18 @@ pub fn validate_and_filter(value: &Value) -> (Value, Vec<FieldViolation>) {
 fn truncate_long_fields(
 	value: &Value,
 	current_path: String,
+	max_length: usize,
 	violations: &mut Vec<FieldViolation>,
 ) -> Value {
 	match value {
 		Value::String(s) => {
-			if s.len() > MAX_FIELD_LENGTH {
+			if s.len() > max_length {
 				violations.push(FieldViolation::new(current_path, s.len()));
-				// Truncate to 491 characters and append "TRUNCATED"
+				// Truncate to leave room for "TRUNCATED"
+				let max_content_length = max_length.saturating_sub(TRUNCATION_MARKER_LENGTH);
 				let truncated = format!(
 					"{}{}",
-					&s[..MAX_CONTENT_LENGTH.min(s.len())],
+					&s[..max_content_length.min(s.len())],
 					TRUNCATION_MARKER
 				);
 				Value::String(truncated)
@@ -58,7 +59,7 @@ fn truncate_long_fields(
 					} else {
 						format!("{}[{}]", current_path, index)
 					};
-					truncate_long_fields(v, path, violations)
+					truncate_long_fields(v, path, max_length, violations)
 				})
 				.collect();
 			Value::Array(truncated_array)
@@ -72,7 +73,7 @@ fn truncate_long_fields(
 					} else {
 						format!("{}.{}", current_path, key)
 					};
-					let truncated_value = truncate_long_fields(v, path, violations);
+					let truncated_value = truncate_long_fields(v, path, max_length, violations);
 					(key.clone(), truncated_value)
 				})
 				.collect();
@@ -83,11 +84,11 @@ fn truncate_long_fields(
 }
 
 /// Formats violations into a human-readable error message
-pub fn format_error_message(violations: &[FieldViolation]) -> String {
+pub fn format_error_message(violations: &[FieldViolation], max_length: usize) -> String {
 	let mut message = format!(
 		"Field length validation failed. The following {} field(s) exceed the {} character limit:\n",
 		violations.len(),
-		MAX_FIELD_LENGTH
+		max_length
 	);
 
 	for violation in violations {
@@ -101,15 +102,15 @@ pub fn format_error_message(violations: &[FieldViolation]) -> String {
 }
 
 /// Creates a JSON error response for field length violations
-pub fn create_error_response(violations: &[FieldViolation]) -> Value {
+pub fn create_error_response(violations: &[FieldViolation], max_length: usize) -> Value {
 	serde_json::json!({
 		"error": "Field length validation failed",
 		"message": format!(
 			"{} field(s) exceed the {} character limit",
 			violations.len(),
-			MAX_FIELD_LENGTH
+			max_length
 		),
-		"limit": MAX_FIELD_LENGTH,
+		"limit": max_length,
 		"violations": violations.iter().map(|v| {
 			serde_json::json!({
 				"field": v.path,
@@ -125,6 +126,8 @@ mod tests {
 	use pretty_assertions::assert_eq;
 	use serde_json::json;
 
+	const LIMIT: usize = crate::config::DEFAULT_MAX_FIELD_LENGTH;
+
 	#[test]
 	fn test_format_error_message() {
 		let violations = vec![
@@ -132,7 +135,7 @@ mod tests {
 			FieldViolation::new("nested.field2".to_string(), 520),
 		];
 
-		let message = format_error_message(&violations);
+		let message = format_error_message(&violations, LIMIT);
 
 		assert!(message.contains("Field length validation failed"));
 		assert!(message.contains("2 field(s)"));
@@ -147,7 +150,7 @@ mod tests {
 	fn test_create_error_response() {
 		let violations = vec![FieldViolation::new("field1".to_string(), 510)];
 
-		let response = create_error_response(&violations);
+		let response = create_error_response(&violations, LIMIT);
 
 		assert_eq!(response["error"], "Field length validation failed");
 		assert_eq!(response["limit"], 500);
@@ -164,7 +167,7 @@ mod tests {
 			"long_field": over_500.clone()
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// Should have one violation
 		assert_eq!(violations.len(), 1);
@@ -189,7 +192,7 @@ mod tests {
 			}
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// No violations
 		assert!(violations.is_empty());
@@ -209,7 +212,7 @@ mod tests {
 			}
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// Should have one violation
 		assert_eq!(violations.len(), 1);
@@ -234,7 +237,7 @@ mod tests {
 			]
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// Should have one violation
 		assert_eq!(violations.len(), 1);
@@ -262,7 +265,7 @@ mod tests {
 			}
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// Should have two violations
 		assert_eq!(violations.len(), 2);
@@ -297,7 +300,7 @@ mod tests {
 			}
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// Should have one violation
 		assert_eq!(violations.len(), 1);
@@ -319,6 +322,20 @@ mod tests {
 		assert!(truncated_desc.ends_with("TRUNCATED"));
 	}
 
+	#[test]
+	fn test_truncate_with_custom_limit() {
+		let data = json!({
+			"field": "a".repeat(60)
+		});
+
+		let (truncated, violations) = validate_and_filter(&data, 50);
+
+		assert_eq!(violations.len(), 1);
+		let truncated_value = truncated["field"].as_str().unwrap();
+		assert_eq!(truncated_value.len(), 50); // 41 + 9 ("TRUNCATED")
+		assert!(truncated_value.ends_with("TRUNCATED"));
+	}
+
 	#[test]
 	fn test_truncate_with_exactly_500_chars() {
 		let exactly_500 = "a".repeat(500);
@@ -326,7 +343,7 @@ mod tests {
 			"field": exactly_500.clone()
 		});
 
-		let (truncated, violations) = validate_and_filter(&data);
+		let (truncated, violations) = validate_and_filter(&data, LIMIT);
 
 		// No violations - exactly 500 is allowed
 		assert!(violations.is_empty());
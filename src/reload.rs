@@ -0,0 +1,114 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time;
+use tracing::{error, info, warn};
+
+use crate::config::{self, Config};
+use crate::metrics::CONFIG_RELOADS;
+use crate::proxy::Settings;
+use crate::upstream;
+
+// k8s ConfigMap updates are symlink swaps, which inotify tends to miss. Polling the mtime doesn't
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Each file the config is read from, with its mtime
+type Modified = Vec<(PathBuf, Option<SystemTime>)>;
+
+/// Swaps the active `Settings` whenever the config file (or its privacy rules file) changes, or
+/// on SIGHUP
+pub struct ConfigReloader {
+	settings: Arc<ArcSwap<Settings>>,
+	path: Option<PathBuf>,
+}
+
+impl ConfigReloader {
+	pub fn new(settings: Arc<ArcSwap<Settings>>) -> Self {
+		Self {
+			settings,
+			path: config::config_path(),
+		}
+	}
+
+	/// The mtimes of the config file and the files it points at. Any change counts, not just a
+	/// newer one, since `cp -p` and secret rotation can put an older file in place
+	fn modified(&self) -> Modified {
+		let mtime = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+		let settings = self.settings.load();
+		let redaction = &settings.conf.redaction;
+		[
+			self.path.as_deref(),
+			redaction.rules_file.as_deref(),
+			redaction.pseudonymize.key_file.as_deref(),
+			redaction.shadow.as_ref().map(|s| s.rules_file.as_path()),
+		]
+		.into_iter()
+		.flatten()
+		.map(|path| (path.to_path_buf(), mtime(path)))
+		.collect()
+	}
+
+	async fn reload(&self) {
+		let current = self.settings.load_full();
+		match Config::load().and_then(|conf| Settings::reload(conf, &current)) {
+			Ok(settings) => {
+				if current.conf.listen != settings.conf.listen
+					|| current.conf.k8s != settings.conf.k8s
+				{
+					warn!(
+						"config reload: `listen` and `k8s` changes need a restart to take effect"
+					);
+				}
+				// Resolve before swapping, so requests never see an empty address cache
+				upstream::resolve_all(&settings).await;
+				self.settings.store(Arc::new(settings));
+				CONFIG_RELOADS.with_label_values(&["success"]).inc();
+				info!("config reloaded");
+			},
+			Err(e) => {
+				CONFIG_RELOADS.with_label_values(&["rejected"]).inc();
+				error!("config reload rejected, keeping the current config: {e}");
+			},
+		}
+	}
+}
+
+#[async_trait]
+impl BackgroundService for ConfigReloader {
+	async fn start(&self, mut shutdown: ShutdownWatch) {
+		let mut hangup = match signal(SignalKind::hangup()) {
+			Ok(hangup) => hangup,
+			Err(e) => {
+				error!("unable to listen for SIGHUP, config reloads are disabled: {e}");
+				return;
+			},
+		};
+		let mut interval = time::interval(POLL_INTERVAL);
+		let mut last_modified = self.modified();
+
+		loop {
+			tokio::select! {
+				_ = shutdown.changed() => return,
+				_ = hangup.recv() => {
+					info!("SIGHUP received, reloading config");
+					self.reload().await;
+					last_modified = self.modified();
+				},
+				_ = interval.tick() => {
+					if self.modified() != last_modified {
+						self.reload().await;
+						// A reload can point at other files
+						last_modified = self.modified();
+					}
+				},
+			}
+		}
+	}
+}
@@ -0,0 +1,374 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Debug, Formatter};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use futures::future::join_all;
+use pingora::lb::discovery::ServiceDiscovery;
+use pingora::lb::health_check::HttpHealthCheck;
+use pingora::lb::selection::RoundRobin;
+use pingora::lb::{Backend, Backends, LoadBalancer};
+use pingora::prelude::HttpPeer;
+use pingora::protocols::ALPN;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::utils::tls::CertKey;
+use pingora::{Error, ErrorType, OrErr, Result};
+use tokio::{net, time};
+use tracing::warn;
+
+use crate::config::{Balance, Config, Upstream};
+use crate::errors::UmamiProxyError;
+use crate::listen;
+use crate::metrics::UPSTREAM_RESOLUTIONS;
+use crate::proxy::Settings;
+pub mod breaker;
+use breaker::Breaker;
+
+// Only bounds the search for a ready backend, round robin finds one in the first step
+const MAX_ITERATIONS: usize = 256;
+
+/// Looks `host` up with tokio's resolver, so a slow DNS server doesn't block a worker thread
+struct DnsDiscovery {
+	host: String,
+	port: u16,
+}
+
+#[async_trait]
+impl ServiceDiscovery for DnsDiscovery {
+	async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+		let error = || ErrorType::Custom(UmamiProxyError::UpstreamUnresolved.into());
+		let backends: BTreeSet<Backend> = net::lookup_host((self.host.as_str(), self.port))
+			.await
+			.or_err_with(error(), || format!("resolving {}:{}", self.host, self.port))?
+			.map(|addr| Backend::new(&addr.to_string()))
+			.collect::<Result<_>>()?;
+		// An empty answer would throw away the addresses we still have
+		if backends.is_empty() {
+			return Error::e_explain(error(), format!("{} resolved to no addresses", self.host));
+		}
+		Ok((backends, HashMap::new()))
+	}
+}
+
+/// Every address an upstream resolves to, their health, the number of requests in flight to
+/// each, and the upstream's circuit breaker
+pub struct Balancer {
+	name: String,
+	balance: Balance,
+	lb: LoadBalancer<RoundRobin>,
+	in_flight: Mutex<HashMap<SocketAddr, usize>>,
+	breaker: Breaker,
+	/// TLS settings shared by every peer, only the address changes
+	peer_template: HttpPeer,
+}
+
+impl Balancer {
+	/// Fails when the upstream's TLS files can't be loaded
+	pub fn new(name: &str, upstream: &Upstream, conf: &Config) -> Result<Self, String> {
+		let discovery = DnsDiscovery {
+			host: upstream.host.clone(),
+			port: upstream.port,
+		};
+		Self::with_discovery(name, upstream, conf, Box::new(discovery))
+	}
+
+	/// `new`, with the addresses coming from `discovery` instead of DNS
+	fn with_discovery(
+		name: &str,
+		upstream: &Upstream,
+		conf: &Config,
+		discovery: Box<dyn ServiceDiscovery + Send + Sync>,
+	) -> Result<Self, String> {
+		let peer_template = peer_template(upstream)?;
+		let mut lb = LoadBalancer::from_backends(Backends::new(discovery));
+		if let Some(path) = &conf.health_check.path {
+			let mut check = HttpHealthCheck::new(&upstream.host, upstream.sni.is_some());
+			// Same TLS as real requests, but keep the health check's short timeouts
+			let options = check.peer_template.options.clone();
+			check.peer_template = peer_template.clone();
+			check.peer_template.options.connection_timeout = options.connection_timeout;
+			check.peer_template.options.read_timeout = options.read_timeout;
+			check.req.set_uri(
+				path.parse()
+					.expect("`health_check.path` is validated to be a path"),
+			);
+			check.consecutive_failure = conf.health_check.unhealthy_after;
+			check.consecutive_success = conf.health_check.healthy_after;
+			lb.set_health_check(Box::new(check));
+		}
+		Ok(Self {
+			name: name.into(),
+			balance: upstream.balance,
+			lb,
+			in_flight: Mutex::default(),
+			breaker: Breaker::new(name, &conf.circuit_breaker),
+			peer_template,
+		})
+	}
+
+	pub const fn breaker(&self) -> &Breaker {
+		&self.breaker
+	}
+
+	/// At least one address passes its health checks and the breaker isn't open
+	pub fn is_ready(&self) -> bool {
+		let backends = self.lb.backends();
+		!self.breaker.is_open()
+			&& backends
+				.get_backend()
+				.iter()
+				.any(|backend| backends.ready(backend))
+	}
+
+	/// Refreshes the cached addresses. A failed lookup keeps the previous ones around
+	pub async fn resolve(&self) {
+		match self.lb.update().await {
+			Ok(()) => {
+				UPSTREAM_RESOLUTIONS
+					.with_label_values(&[self.name.as_str(), "success"])
+					.inc();
+			},
+			Err(e) => {
+				UPSTREAM_RESOLUTIONS
+					.with_label_values(&[self.name.as_str(), "failure"])
+					.inc();
+				warn!("upstream '{}': {e}", self.name);
+			},
+		}
+	}
+
+	/// Picks an address for one request
+	pub async fn lease(self: &Arc<Self>) -> Result<Lease> {
+		if self.lb.backends().get_backend().is_empty() {
+			// Nothing cached yet, typically the first requests after startup
+			self.resolve().await;
+		}
+
+		let mut in_flight = self
+			.in_flight
+			.lock()
+			.expect("Failed to lock in-flight counts");
+		let backend = match self.balance {
+			Balance::RoundRobin => self.lb.select(b"", MAX_ITERATIONS),
+			Balance::LeastConnections => {
+				let backends = self.lb.backends();
+				backends
+					.get_backend()
+					.iter()
+					.filter(|backend| backends.ready(backend))
+					.min_by_key(|backend| {
+						backend
+							.as_inet()
+							.and_then(|addr| in_flight.get(addr))
+							.copied()
+							.unwrap_or_default()
+					})
+					.cloned()
+			},
+		};
+		let addr = backend
+			.as_ref()
+			.and_then(|backend| backend.as_inet())
+			.copied()
+			.ok_or_else(|| {
+				Error::explain(
+					ErrorType::Custom(UmamiProxyError::NoMatchingPeer.into()),
+					format!("upstream '{}' has no resolved addresses", self.name),
+				)
+			})?;
+		*in_flight.entry(addr).or_default() += 1;
+
+		Ok(Lease {
+			balancer: Arc::clone(self),
+			addr,
+		})
+	}
+}
+
+impl Debug for Balancer {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		f.debug_struct("Balancer")
+			.field("name", &self.name)
+			.field("balance", &self.balance)
+			.field("backends", &self.lb.backends().get_backend())
+			.finish_non_exhaustive()
+	}
+}
+
+/// One request's claim on an upstream address. It's kept in `Ctx`, so the in-flight count goes
+/// back down whenever the request ends, however it ends
+pub struct Lease {
+	balancer: Arc<Balancer>,
+	pub addr: SocketAddr,
+}
+
+impl Lease {
+	pub fn breaker(&self) -> &Breaker {
+		&self.balancer.breaker
+	}
+
+	pub fn peer(&self) -> HttpPeer {
+		let mut peer = self.balancer.peer_template.clone();
+		peer._address = pingora::protocols::l4::socket::SocketAddr::Inet(self.addr);
+		peer
+	}
+}
+
+/// TLS is on when `sni` is set, `tls` tunes it
+fn peer_template(upstream: &Upstream) -> Result<HttpPeer, String> {
+	let tls = &upstream.tls;
+	let mut peer = HttpPeer::new(
+		"0.0.0.0:1",
+		upstream.sni.is_some(),
+		upstream.sni.clone().unwrap_or_default(),
+	);
+	peer.options.verify_cert = tls.verify_cert;
+	peer.options.verify_hostname = tls.verify_hostname;
+	peer.options.alternative_cn.clone_from(&tls.alternative_cn);
+	peer.options.alpn = if tls.http2 { ALPN::H2H1 } else { ALPN::H1 };
+	if let Some(ca) = &tls.ca {
+		peer.options.ca = Some(Arc::new(listen::read_certs(ca)?.into_boxed_slice()));
+	}
+	if let Some(client) = &tls.client {
+		let (chain, key) = listen::read_pair(client)?;
+		peer.client_cert_key = Some(Arc::new(CertKey::new(chain, key)));
+	}
+	Ok(peer)
+}
+
+impl Drop for Lease {
+	fn drop(&mut self) {
+		let mut in_flight = self
+			.balancer
+			.in_flight
+			.lock()
+			.expect("Failed to lock in-flight counts");
+		if let Some(count) = in_flight.get_mut(&self.addr) {
+			*count -= 1;
+			if *count == 0 {
+				in_flight.remove(&self.addr);
+			}
+		}
+	}
+}
+
+impl Debug for Lease {
+	fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+		write!(f, "Lease({} -> {})", self.balancer.name, self.addr)
+	}
+}
+
+pub async fn resolve_all(settings: &Settings) {
+	join_all(settings.balancers().map(|balancer| balancer.resolve())).await;
+}
+
+async fn check_all(settings: &Settings) {
+	join_all(
+		settings
+			.balancers()
+			.map(|balancer| balancer.lb.backends().run_health_check(true)),
+	)
+	.await;
+}
+
+/// Re-resolves the upstreams of whatever settings are active every `dns.refresh_secs`, and
+/// health checks them every `health_check.interval_secs`
+pub struct UpstreamMonitor {
+	settings: Arc<ArcSwap<Settings>>,
+}
+
+impl UpstreamMonitor {
+	pub const fn new(settings: Arc<ArcSwap<Settings>>) -> Self {
+		Self { settings }
+	}
+}
+
+#[async_trait]
+impl BackgroundService for UpstreamMonitor {
+	async fn start(&self, mut shutdown: ShutdownWatch) {
+		let mut next_resolve = Instant::now();
+		let mut next_check = Instant::now();
+		loop {
+			// Intervals are read every round, so they follow config reloads
+			let settings = self.settings.load_full();
+			let now = Instant::now();
+			if next_resolve <= now {
+				resolve_all(&settings).await;
+				next_resolve = now + Duration::from_secs(settings.conf.dns.refresh_secs);
+			}
+			if next_check <= now {
+				check_all(&settings).await;
+				next_check = now + Duration::from_secs(settings.conf.health_check.interval_secs);
+			}
+			tokio::select! {
+				_ = shutdown.changed() => return,
+				() = time::sleep_until(next_resolve.min(next_check).into()) => {},
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::Upstream;
+	use pretty_assertions::assert_eq;
+
+	fn localhost(balance: Balance) -> Arc<Balancer> {
+		let upstream = Upstream {
+			host: "127.0.0.1".into(),
+			port: 3000,
+			balance,
+			..Upstream::default()
+		};
+		Arc::new(Balancer::new("test", &upstream, &Config::default()).unwrap())
+	}
+
+	#[tokio::test]
+	async fn test_lease_resolves_on_first_use_and_counts_in_flight() {
+		let balancer = localhost(Balance::LeastConnections);
+		let first = balancer.lease().await.unwrap();
+		let second = balancer.lease().await.unwrap();
+		assert_eq!(first.addr, "127.0.0.1:3000".parse().unwrap());
+		assert_eq!(balancer.in_flight.lock().unwrap()[&first.addr], 2);
+
+		drop(first);
+		drop(second);
+		assert!(balancer.in_flight.lock().unwrap().is_empty());
+	}
+
+	/// Fails like `DnsDiscovery` does for a name that doesn't resolve, without asking DNS
+	struct Unresolvable;
+
+	#[async_trait]
+	impl ServiceDiscovery for Unresolvable {
+		async fn discover(&self) -> Result<(BTreeSet<Backend>, HashMap<u64, bool>)> {
+			Error::e_explain(
+				ErrorType::Custom(UmamiProxyError::UpstreamUnresolved.into()),
+				"resolving umami.invalid:3000",
+			)
+		}
+	}
+
+	#[tokio::test]
+	async fn test_unresolvable_host_is_an_error() {
+		let balancer = Balancer::with_discovery(
+			"test",
+			&Upstream::default(),
+			&Config::default(),
+			Box::new(Unresolvable),
+		);
+		let balancer = Arc::new(balancer.unwrap());
+		let Err(e) = balancer.lease().await else {
+			panic!("An unresolvable host shouldn't produce a peer");
+		};
+		assert_eq!(
+			e.etype,
+			ErrorType::Custom(UmamiProxyError::NoMatchingPeer.into())
+		);
+	}
+}
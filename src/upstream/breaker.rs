@@ -0,0 +1,262 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::CircuitBreaker;
+use crate::metrics::{CIRCUIT_BREAKER_STATE, CIRCUIT_BREAKER_TRIPS};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+	Closed {
+		failures: u32,
+	},
+	Open {
+		until: Instant,
+	},
+	/// One trial request is out, and only its result counts. If it never reports back (it failed
+	/// before reaching the upstream), another one is let through once `until` has passed
+	HalfOpen {
+		until: Instant,
+		trial: u64,
+	},
+}
+
+impl State {
+	const fn gauge(self) -> i64 {
+		match self {
+			Self::Closed { .. } => 0,
+			Self::Open { .. } => 1,
+			Self::HalfOpen { .. } => 2,
+		}
+	}
+}
+
+/// What `allow` hands a request, to give back with its result. Only the trial's result decides
+/// a half-open breaker, not those of requests that were let through before it opened
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Permit {
+	trial: Option<u64>,
+}
+
+/// Closed -> open after `failure_threshold` consecutive upstream failures, open -> half-open after
+/// `open_secs`, and the trial request in half-open decides whether it closes or opens again
+#[derive(Debug)]
+pub struct Breaker {
+	upstream: String,
+	/// `None` when the breaker is disabled
+	threshold: Option<u32>,
+	open_for: Duration,
+	state: Mutex<State>,
+	trials: AtomicU64,
+}
+
+impl Breaker {
+	pub fn new(upstream: &str, conf: &CircuitBreaker) -> Self {
+		let breaker = Self {
+			upstream: upstream.into(),
+			threshold: conf.enabled.then_some(conf.failure_threshold),
+			open_for: Duration::from_secs(conf.open_secs),
+			state: Mutex::new(State::Closed { failures: 0 }),
+			trials: AtomicU64::new(0),
+		};
+		breaker.publish(State::Closed { failures: 0 });
+		breaker
+	}
+
+	/// Whether a request may go to the upstream, `None` if not. Claims the trial slot when
+	/// half-open
+	pub fn allow(&self) -> Option<Permit> {
+		let now = Instant::now();
+		let mut state = self.state.lock().expect("Failed to lock breaker");
+		match *state {
+			State::Closed { .. } => Some(Permit { trial: None }),
+			State::Open { until } | State::HalfOpen { until, .. } if now >= until => {
+				let trial = self.trials.fetch_add(1, Ordering::Relaxed);
+				self.transition(
+					&mut state,
+					State::HalfOpen {
+						until: now + self.open_for,
+						trial,
+					},
+				);
+				Some(Permit { trial: Some(trial) })
+			},
+			State::Open { .. } | State::HalfOpen { .. } => None,
+		}
+	}
+
+	/// Seconds until a request might be let through again
+	pub fn retry_after(&self) -> u64 {
+		match *self.state.lock().expect("Failed to lock breaker") {
+			State::Closed { .. } => 0,
+			State::Open { until } | State::HalfOpen { until, .. } => until
+				.saturating_duration_since(Instant::now())
+				.as_secs()
+				.max(1),
+		}
+	}
+
+	pub fn is_open(&self) -> bool {
+		matches!(
+			*self.state.lock().expect("Failed to lock breaker"),
+			State::Open { .. }
+		)
+	}
+
+	pub fn record_success(&self, permit: Permit) {
+		let mut state = self.state.lock().expect("Failed to lock breaker");
+		if counts(*state, permit) {
+			self.transition(&mut state, State::Closed { failures: 0 });
+		}
+	}
+
+	pub fn record_failure(&self, permit: Permit) {
+		let Some(threshold) = self.threshold else {
+			return;
+		};
+		let mut state = self.state.lock().expect("Failed to lock breaker");
+		if !counts(*state, permit) {
+			return;
+		}
+		let next = match *state {
+			State::Closed { failures } if failures + 1 < threshold => State::Closed {
+				failures: failures + 1,
+			},
+			State::Closed { .. } | State::HalfOpen { .. } => {
+				CIRCUIT_BREAKER_TRIPS
+					.with_label_values(&[self.upstream.as_str()])
+					.inc();
+				State::Open {
+					until: Instant::now() + self.open_for,
+				}
+			},
+			open @ State::Open { .. } => open,
+		};
+		self.transition(&mut state, next);
+	}
+
+	fn transition(&self, state: &mut State, next: State) {
+		if state.gauge() != next.gauge() {
+			self.publish(next);
+		}
+		*state = next;
+	}
+
+	fn publish(&self, state: State) {
+		CIRCUIT_BREAKER_STATE
+			.with_label_values(&[self.upstream.as_str()])
+			.set(state.gauge());
+	}
+}
+
+/// Requests that were already in flight when it opened don't count while it's open or half-open,
+/// only the trial does
+fn counts(state: State, permit: Permit) -> bool {
+	match state {
+		State::Closed { .. } => true,
+		State::Open { .. } => false,
+		State::HalfOpen { trial, .. } => permit.trial == Some(trial),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn breaker(open_secs: u64) -> Breaker {
+		Breaker::new(
+			"test",
+			&CircuitBreaker {
+				enabled: true,
+				failure_threshold: 3,
+				open_secs,
+			},
+		)
+	}
+
+	#[test]
+	fn test_opens_after_consecutive_failures() {
+		let breaker = breaker(30);
+		let permit = breaker.allow().unwrap();
+		let in_flight = breaker.allow().unwrap();
+		breaker.record_failure(permit);
+		breaker.record_failure(permit);
+		breaker.record_success(permit);
+		breaker.record_failure(permit);
+		breaker.record_failure(permit);
+		assert!(breaker.allow().is_some(), "A success resets the count");
+
+		breaker.record_failure(permit);
+		assert!(breaker.is_open());
+		assert!(breaker.allow().is_none());
+		assert!(breaker.retry_after() > 0);
+
+		breaker.record_success(in_flight);
+		assert!(
+			breaker.is_open(),
+			"A request that was in flight when it opened doesn't close it"
+		);
+	}
+
+	#[test]
+	fn test_half_open_lets_one_trial_through() {
+		let breaker = breaker(0);
+		let in_flight = breaker.allow().unwrap();
+		for _ in 0..3 {
+			breaker.record_failure(in_flight);
+		}
+		let trial = breaker
+			.allow()
+			.expect("open_secs has passed, so this is the trial");
+
+		breaker.record_failure(trial);
+		assert!(breaker.is_open(), "A failed trial opens it again");
+
+		let trial = breaker.allow().unwrap();
+		breaker.record_success(trial);
+		assert!(breaker.allow().is_some());
+		assert!(!breaker.is_open());
+	}
+
+	#[test]
+	fn test_only_the_trial_decides_half_open() {
+		let breaker = breaker(30);
+		let in_flight = breaker.allow().unwrap();
+		let late = breaker.allow().unwrap();
+		for _ in 0..3 {
+			breaker.record_failure(in_flight);
+		}
+		// Skip the wait for `open_secs`
+		*breaker.state.lock().unwrap() = State::Open {
+			until: Instant::now(),
+		};
+		let trial = breaker.allow().unwrap();
+
+		breaker.record_success(late);
+		assert!(
+			breaker.allow().is_none(),
+			"A request from before it opened doesn't close it"
+		);
+		breaker.record_failure(late);
+		assert!(!breaker.is_open(), "Nor does it open it again");
+
+		breaker.record_success(trial);
+		assert!(breaker.allow().is_some());
+	}
+
+	#[test]
+	fn test_disabled_never_opens() {
+		let breaker = Breaker::new(
+			"test",
+			&CircuitBreaker {
+				enabled: false,
+				..CircuitBreaker::default()
+			},
+		);
+		let permit = breaker.allow().unwrap();
+		for _ in 0..100 {
+			breaker.record_failure(permit);
+		}
+		assert!(breaker.allow().is_some());
+	}
+}